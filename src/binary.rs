@@ -0,0 +1,261 @@
+//! Minimal binary coordinator protocol over TCP, used for `tcp://` coordinator urls
+//!
+//! A lighter alternative to JSON over http for many workers. Every message is a frame of a
+//! big-endian `u16` body length followed by the body, one request and response per connection.
+//! All integers are big-endian
+//!
+//! | Message  | Body                                                            |
+//! |----------|-----------------------------------------------------------------|
+//! | Request  | `op: u8` (1 claim, 2 re-verify), `id: u16` (worker id, 0 for claims) |
+//! | Response | `status: u16` (200 or a http status), if 200 `id: u16, ts: u64, re_ts: u64` |
+//!
+//! Only claims and re-verifies are supported, the /time, /lookup and long-poll requests need a
+//! http coordinator. Leases don't report a layout, see [crate::Lease::layout], nor regions, see
+//! [crate::Snowflake::usage_for_region]
+
+use crate::{Lease, SnowflakeError, WorkerId};
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Write};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    crate::coordinator::{self, Coordinator},
+    crate::SnowflakeConfig,
+    std::net::{TcpStream, ToSocketAddrs},
+    std::time::Duration,
+};
+
+const OP_CLAIM: u8 = 1;
+const OP_REVERIFY: u8 = 2;
+const STATUS_OK: u16 = 200;
+
+/// Request sent to the coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Claim a free worker id
+    Claim,
+    /// Renew the lease of the worker id
+    Reverify(WorkerId),
+}
+
+/// Response sent by the coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    /// Granted or renewed lease
+    Lease(Lease),
+    /// Refused with a non-200 status
+    Status(u16),
+}
+
+fn invalid(reason: &str) -> SnowflakeError {
+    SnowflakeError::InvalidResponse(format!("binary protocol: {}", reason))
+}
+
+impl Request {
+    /// Body of the request frame
+    pub fn encode(&self) -> Vec<u8> {
+        let (op, id) = match self {
+            Request::Claim => (OP_CLAIM, 0),
+            Request::Reverify(id) => (OP_REVERIFY, *id),
+        };
+        let mut body = vec![op];
+        body.extend_from_slice(&id.to_be_bytes());
+        body
+    }
+
+    /// Parses the body of a request frame
+    pub fn decode(body: &[u8]) -> Result<Self, SnowflakeError> {
+        match body {
+            [OP_CLAIM, _, _] => Ok(Request::Claim),
+            [OP_REVERIFY, hi, lo] => Ok(Request::Reverify(u16::from_be_bytes([*hi, *lo]))),
+            [_, _, _] => Err(invalid("unknown op")),
+            _ => Err(invalid("request body isn't 3 bytes")),
+        }
+    }
+}
+
+impl Response {
+    /// Body of the response frame
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Response::Lease(lease) => {
+                let mut body = STATUS_OK.to_be_bytes().to_vec();
+                body.extend_from_slice(&lease.id.to_be_bytes());
+                body.extend_from_slice(&lease.ts.to_be_bytes());
+                body.extend_from_slice(&lease.re_ts.to_be_bytes());
+                body
+            }
+            Response::Status(status) => status.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Parses the body of a response frame
+    pub fn decode(body: &[u8]) -> Result<Self, SnowflakeError> {
+        if body.len() < 2 {
+            return Err(invalid("response body is missing the status"));
+        }
+        let status = u16::from_be_bytes([body[0], body[1]]);
+        if status != STATUS_OK {
+            return Ok(Response::Status(status));
+        }
+        if body.len() != 20 {
+            return Err(invalid("lease body isn't 20 bytes"));
+        }
+        let u64_at = |i: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&body[i..i + 8]);
+            u64::from_be_bytes(bytes)
+        };
+        Ok(Response::Lease(Lease {
+            id: u16::from_be_bytes([body[2], body[3]]),
+            ts: u64_at(4),
+            re_ts: u64_at(12),
+            layout: None,
+        }))
+    }
+}
+
+/// Writes `body` as length-prefixed frame
+pub fn write_frame(w: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(body.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame body too long"))?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(body)?;
+    w.flush()
+}
+
+/// Reads the body of a length-prefixed frame
+pub fn read_frame(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 2];
+    r.read_exact(&mut len)?;
+    let mut body = vec![0; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Client of a `tcp://host:port` coordinator
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub(crate) struct TcpCoordinator {
+    config: SnowflakeConfig,
+    addr: String,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TcpCoordinator {
+    pub(crate) fn new(config: SnowflakeConfig) -> Self {
+        let addr = config
+            .coordinator_url
+            .trim_start_matches("tcp://")
+            .trim_end_matches('/')
+            .to_string();
+        TcpCoordinator { config, addr }
+    }
+
+    /// Sends `request` on a new connection, waiting while too many requests are in flight
+    fn call(&self, request: Request, timeout: Option<Duration>) -> Result<Lease, SnowflakeError> {
+        let response = coordinator::limited(&self.config, || {
+            self.exchange(request, timeout)
+                .map_err(|e| SnowflakeError::Transport(e.to_string()))
+        })?;
+        match Response::decode(&response)? {
+            Response::Lease(lease) => Ok(lease),
+            Response::Status(status) => Err(SnowflakeError::Status(status)),
+        }
+    }
+
+    fn exchange(&self, request: Request, timeout: Option<Duration>) -> io::Result<Vec<u8>> {
+        let mut stream = match timeout {
+            Some(timeout) => {
+                let addr = self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "coordinator address not found")
+                })?;
+                TcpStream::connect_timeout(&addr, timeout)?
+            }
+            None => TcpStream::connect(&self.addr)?,
+        };
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        write_frame(&mut stream, &request.encode())?;
+        read_frame(&mut stream)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Coordinator for TcpCoordinator {
+    fn claim(&self, timeout: Option<Duration>) -> Result<Lease, SnowflakeError> {
+        self.call(Request::Claim, timeout.or(self.config.request_timeout))
+    }
+
+    fn reverify(&self, id: WorkerId) -> Result<Lease, SnowflakeError> {
+        self.call(Request::Reverify(id), self.config.request_timeout)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::binary::{read_frame, write_frame, Request, Response};
+    use crate::{mock, Generator, Lease, SnowflakeConfig, PRE_TIME};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn test_round_trip() {
+        for request in [Request::Claim, Request::Reverify(513)] {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+        let lease = Response::Lease(Lease {
+            id: 7,
+            ts: 1_623_801_600,
+            re_ts: 1_623_805_200,
+            layout: None,
+        });
+        assert_eq!(Response::decode(&lease.encode()).unwrap(), lease);
+        assert_eq!(
+            Response::decode(&Response::Status(409).encode()).unwrap(),
+            Response::Status(409)
+        );
+        assert!(Response::decode(&[0, 200, 1]).is_err());
+        assert!(Request::decode(&[9, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_claim_and_reverify() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reverifies = Arc::new(AtomicUsize::new(0));
+        let seen = reverifies.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let request = Request::decode(&read_frame(&mut stream).unwrap()).unwrap();
+                if let Request::Reverify(id) = request {
+                    assert_eq!(id, 7);
+                    seen.fetch_add(1, Ordering::SeqCst);
+                }
+                // Re-verify a second after the claim
+                let lease = Lease {
+                    id: 7,
+                    ts: mock::now_secs(),
+                    re_ts: mock::now_secs() + PRE_TIME + 1,
+                    layout: None,
+                };
+                write_frame(&mut stream, &Response::Lease(lease).encode()).unwrap();
+            }
+        });
+
+        let config = SnowflakeConfig::new(format!("tcp://{}", addr));
+        config.validate().unwrap();
+        let generator = Generator::with_config(config);
+        assert_eq!(generator.generate(0).await.worker_id, 7);
+        let start = Instant::now();
+        while generator.reverify_state().last_success.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(5), "no re-verify");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(reverifies.load(Ordering::SeqCst) >= 1);
+    }
+}
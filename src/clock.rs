@@ -0,0 +1,232 @@
+//! Time sources for snowflake generation
+
+use crate::NanoTimestamp;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time
+pub trait Clock: Debug + Send + Sync {
+    /// Nano secs since unix epoch
+    fn now_nanos(&self) -> NanoTimestamp;
+}
+
+/// Reads the system wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos()
+    }
+}
+
+/// Offset of [TestClock] from the system clock in nano secs
+#[cfg(feature = "testing")]
+static TEST_OFFSET: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+
+/// Process wide clock of the global generator, tests move it with [TestClock::advance] and
+/// [TestClock::set]
+///
+/// Only exists with the `testing` feature, release builds always read the system clock. It's
+/// offset from the system clock instead of frozen, the generator waits for the clock once a tick
+/// is exhausted and would stall otherwise. Setting it back makes the generator treat it as a clock
+/// regression
+#[cfg(feature = "testing")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TestClock;
+
+#[cfg(feature = "testing")]
+impl TestClock {
+    /// Moves the clock `by` forward
+    pub fn advance(by: Duration) {
+        TEST_OFFSET.fetch_add(by.as_nanos() as i64, Ordering::SeqCst);
+    }
+
+    /// Moves the clock to `time`, from where it keeps running at the system clock rate
+    pub fn set(time: SystemTime) {
+        let target = time
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos() as i64;
+        TEST_OFFSET.store(target - SystemClock.now_nanos() as i64, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "testing")]
+impl Clock for TestClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        let offset = TEST_OFFSET.load(Ordering::SeqCst) as NanoTimestamp;
+        SystemClock.now_nanos().wrapping_add(offset)
+    }
+}
+
+/// Nano secs elapsed since creation, from [Instant]
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+struct Elapsed(Instant);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for Elapsed {
+    fn now_nanos(&self) -> NanoTimestamp {
+        self.0.elapsed().as_nanos()
+    }
+}
+
+/// Wall time derived from a monotonic clock, unaffected by NTP steps
+///
+/// Anchored to the wall clock on creation. Every reading compares the wall clock with the derived
+/// time, a divergence beyond `max_divergence` (e.g. NTP stepping the clock) is logged, counted and
+/// optionally re-anchors to the wall clock
+#[derive(Debug)]
+pub struct MonotonicClock {
+    wall: Arc<dyn Clock>,
+    monotonic: Arc<dyn Clock>,
+    max_divergence: NanoTimestamp,
+    re_anchor: bool,
+    state: Mutex<Anchor>,
+    divergences: AtomicU64,
+}
+
+/// Wall and monotonic reading taken at the same time
+#[derive(Debug)]
+struct Anchor {
+    wall: NanoTimestamp,
+    monotonic: NanoTimestamp,
+    /// If the last reading diverged, so a divergence is only counted once
+    diverged: bool,
+}
+
+impl MonotonicClock {
+    /// Derives the time from [Instant], anchored to the system wall clock
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(max_divergence: Duration, re_anchor: bool) -> Self {
+        MonotonicClock::with_sources(
+            Arc::new(SystemClock),
+            Arc::new(Elapsed(Instant::now())),
+            max_divergence,
+            re_anchor,
+        )
+    }
+
+    /// Derives the time from `monotonic`, anchored to `wall`
+    pub fn with_sources(
+        wall: Arc<dyn Clock>,
+        monotonic: Arc<dyn Clock>,
+        max_divergence: Duration,
+        re_anchor: bool,
+    ) -> Self {
+        let anchor = Anchor {
+            wall: wall.now_nanos(),
+            monotonic: monotonic.now_nanos(),
+            diverged: false,
+        };
+        MonotonicClock {
+            wall,
+            monotonic,
+            max_divergence: max_divergence.as_nanos(),
+            re_anchor,
+            state: Mutex::new(anchor),
+            divergences: AtomicU64::new(0),
+        }
+    }
+
+    /// Times the wall clock diverged beyond the threshold
+    pub fn divergences(&self) -> u64 {
+        self.divergences.load(Ordering::Relaxed)
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        let monotonic = self.monotonic.now_nanos();
+        let wall = self.wall.now_nanos();
+        let mut anchor = self.state.lock().expect("Couldn't lock monotonic clock");
+        let derived = anchor.wall + monotonic.saturating_sub(anchor.monotonic);
+        let divergence = wall.abs_diff(derived);
+        if divergence <= self.max_divergence {
+            anchor.diverged = false;
+            return derived;
+        }
+        if !anchor.diverged {
+            self.divergences.fetch_add(1, Ordering::Relaxed);
+            log::warn!(
+                "Wall clock diverged from monotonic time by {} ns{}",
+                divergence,
+                if self.re_anchor { ", re-anchoring" } else { "" }
+            );
+        }
+        if self.re_anchor {
+            *anchor = Anchor {
+                wall,
+                monotonic,
+                diverged: false,
+            };
+            return wall;
+        }
+        anchor.diverged = true;
+        derived
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::clock::{Clock, MonotonicClock};
+    use crate::mock::ManualClock;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn clocks() -> (Arc<ManualClock>, Arc<ManualClock>) {
+        let wall = Arc::new(ManualClock::default());
+        wall.set(1_000_000);
+        (wall, Arc::new(ManualClock::default()))
+    }
+
+    #[test]
+    pub fn test_divergence() {
+        let (wall, monotonic) = clocks();
+        let clock = MonotonicClock::with_sources(
+            wall.clone(),
+            monotonic.clone(),
+            Duration::from_nanos(1_000),
+            false,
+        );
+        monotonic.set(500);
+        wall.set(1_000_600);
+        assert_eq!(clock.now_nanos(), 1_000_500);
+        assert_eq!(clock.divergences(), 0);
+
+        // NTP steps the wall clock forward, the derived time keeps going
+        wall.set(2_000_000);
+        monotonic.set(600);
+        assert_eq!(clock.now_nanos(), 1_000_600);
+        monotonic.set(700);
+        assert_eq!(clock.now_nanos(), 1_000_700);
+        assert_eq!(clock.divergences(), 1);
+    }
+
+    #[test]
+    pub fn test_re_anchor() {
+        let (wall, monotonic) = clocks();
+        let clock = MonotonicClock::with_sources(
+            wall.clone(),
+            monotonic.clone(),
+            Duration::from_nanos(1_000),
+            true,
+        );
+        wall.set(2_000_000);
+        monotonic.set(600);
+        assert_eq!(clock.now_nanos(), 2_000_000);
+        monotonic.set(700);
+        wall.set(2_000_100);
+        assert_eq!(clock.now_nanos(), 2_000_100);
+        assert_eq!(clock.divergences(), 1);
+    }
+}
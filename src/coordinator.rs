@@ -0,0 +1,1194 @@
+//! Talks to the coordinator handing out worker ids
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::ReverifyFailurePolicy;
+use crate::{
+    otel, usage, CoordinatorTimestamp, Layout, NanoTimestamp, SnowflakeConfig, SnowflakeError,
+    UsageId, WorkerId, PRE_TIME,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Holds response for / request
+///
+/// Common alternative field names of other coordinators are accepted as aliases. The renewal is
+/// either absolute (`re_ts`) or relative to now (`ttl_seconds`, e.g. Redis or etcd backed)
+#[derive(Deserialize, Debug)]
+pub(crate) struct CoordinatorResponse {
+    /// Worker id of requester
+    #[serde(alias = "worker_id")]
+    pub id: WorkerId,
+    /// Request timestamp
+    #[serde(alias = "timestamp")]
+    pub ts: CoordinatorTimestamp,
+    /// Last accepted timestamp, before id is given out again
+    #[serde(alias = "renew_at")]
+    pub re_ts: Option<CoordinatorTimestamp>,
+    /// Seconds from now the lease is valid for, if `re_ts` is missing
+    #[serde(alias = "ttl")]
+    pub ttl_seconds: Option<u64>,
+    /// Layout the coordinator expects, if it reports one
+    #[serde(default)]
+    pub layout: Option<LeaseLayout>,
+    /// Region names of usage ids, loaded into the usage registry on claim
+    #[serde(default)]
+    pub regions: BTreeMap<UsageId, String>,
+}
+
+impl CoordinatorResponse {
+    /// Absolute re-verify time, computed from the TTL if the response is relative
+    fn re_ts(&self) -> Result<CoordinatorTimestamp, SnowflakeError> {
+        match (self.re_ts, self.ttl_seconds) {
+            (Some(re_ts), _) => Ok(re_ts),
+            (None, Some(ttl)) => Ok(local_ts().saturating_add(ttl)),
+            (None, None) => Err(SnowflakeError::InvalidResponse(
+                "neither re_ts nor ttl_seconds given".to_string(),
+            )),
+        }
+    }
+}
+
+/// Worker id lease handed out by a coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    /// Leased worker id
+    pub id: WorkerId,
+    /// Coordinator unix time in seconds
+    pub ts: CoordinatorTimestamp,
+    /// Unix time in seconds the lease has to be re-verified by
+    pub re_ts: CoordinatorTimestamp,
+    /// Layout the coordinator expects, must match the configured one if reported
+    pub layout: Option<LeaseLayout>,
+}
+
+/// Field widths and epoch of a [Layout] as reported by a coordinator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaseLayout {
+    /// Width of the timestamp field
+    pub timestamp_bits: u8,
+    /// Width of the worker id field
+    pub worker_bits: u8,
+    /// Width of the sequence id field
+    pub sequence_bits: u8,
+    /// Width of the usage id field
+    pub usage_bits: u8,
+    /// Start of the timestamp field as nano secs since unix epoch
+    pub epoch: NanoTimestamp,
+}
+
+impl From<&Layout> for LeaseLayout {
+    fn from(layout: &Layout) -> Self {
+        LeaseLayout {
+            timestamp_bits: layout.timestamp_bits,
+            worker_bits: layout.worker_bits,
+            sequence_bits: layout.sequence_bits,
+            usage_bits: layout.usage_bits,
+            epoch: layout.epoch,
+        }
+    }
+}
+
+/// Errors if `lease` reports a layout other then the configured one
+///
+/// Adopting it mid-run would break the continuity of generated snowflakes
+fn check_layout(config: &SnowflakeConfig, lease: &Lease) -> Result<(), SnowflakeError> {
+    let expected = LeaseLayout::from(&config.layout);
+    match lease.layout {
+        Some(reported) if reported != expected => {
+            Err(SnowflakeError::LayoutChanged { expected, reported })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Records `e` as last error of the re-verify task, which then stops
+///
+/// Later generations fail with [SnowflakeError::ReverifyStopped], the re-verify thread panics
+/// too under [ReverifyFailurePolicy::Panic]
+#[cfg(not(target_arch = "wasm32"))]
+fn fatal(config: &SnowflakeConfig, state: &SharedReverifyState, e: impl std::fmt::Display) {
+    log::error!("Stopping snowflake re-verify: {}", e);
+    // Stopped first, so generations already fail once last_error reports why
+    let _ = state.stopped.set(e.to_string());
+    update(state, |s| {
+        s.last_error = Some(e.to_string());
+        s.next_attempt = None;
+    });
+    if config.on_reverify_failure == ReverifyFailurePolicy::Panic {
+        panic!("{}", e)
+    }
+}
+
+impl TryFrom<CoordinatorResponse> for Lease {
+    type Error = SnowflakeError;
+
+    fn try_from(cr: CoordinatorResponse) -> Result<Self, Self::Error> {
+        Ok(Lease {
+            re_ts: cr.re_ts()?,
+            id: cr.id,
+            ts: cr.ts,
+            layout: cr.layout,
+        })
+    }
+}
+
+/// Protocol used to claim and re-verify worker ids
+///
+/// The url scheme of [SnowflakeConfig::coordinator_url] picks the built-in one, `http(s)://` for
+/// JSON over http and `tcp://` for [crate::binary]. Both are blocking and called off the hot path
+pub trait Coordinator: Debug + Send + Sync {
+    /// Claims a free worker id, `timeout` replaces the configured request timeout if set
+    fn claim(&self, timeout: Option<Duration>) -> Result<Lease, SnowflakeError>;
+
+    /// Renews the lease of worker id `id`
+    fn reverify(&self, id: WorkerId) -> Result<Lease, SnowflakeError>;
+
+    /// Called once a claimed `lease` passed the skew, layout and expiry checks and is used
+    fn accepted(&self, _lease: &Lease) {}
+}
+
+/// JSON over http, `GET /` claims and `GET /reverify/{id}` renews
+#[derive(Debug)]
+pub(crate) struct HttpCoordinator {
+    config: SnowflakeConfig,
+    /// Regions reported with the last claim, registered once its lease is accepted
+    regions: Mutex<Option<(WorkerId, BTreeMap<UsageId, String>)>>,
+}
+
+impl Coordinator for HttpCoordinator {
+    fn claim(&self, timeout: Option<Duration>) -> Result<Lease, SnowflakeError> {
+        let claim_config = SnowflakeConfig {
+            request_timeout: timeout.or(self.config.request_timeout),
+            ..self.config.clone()
+        };
+        let mut cr = request::<CoordinatorResponse>(&claim_config, &self.config.coordinator_url)?;
+        let regions = (cr.id, std::mem::take(&mut cr.regions));
+        let lease = Lease::try_from(cr)?;
+        *self.regions.lock().expect("Couldn't lock claimed regions") = Some(regions);
+        Ok(lease)
+    }
+
+    fn reverify(&self, id: WorkerId) -> Result<Lease, SnowflakeError> {
+        let url = format!("{}/reverify/{}", self.config.coordinator_url, id);
+        request::<CoordinatorResponse>(&self.config, &url).and_then(Lease::try_from)
+    }
+
+    fn accepted(&self, lease: &Lease) {
+        let claimed = self
+            .regions
+            .lock()
+            .expect("Couldn't lock claimed regions")
+            .take();
+        if let Some((_, regions)) = claimed.filter(|(id, _)| *id == lease.id) {
+            for (usage_id, name) in regions {
+                usage::register(usage_id, name);
+            }
+        }
+    }
+}
+
+/// Returns the built-in coordinator for the url scheme of `config`
+pub(crate) fn connect(config: &SnowflakeConfig) -> Arc<dyn Coordinator> {
+    #[cfg(not(target_arch = "wasm32"))]
+    if config.coordinator_url.starts_with("tcp://") {
+        return Arc::new(crate::binary::TcpCoordinator::new(config.clone()));
+    }
+    Arc::new(HttpCoordinator {
+        config: config.clone(),
+        regions: Mutex::default(),
+    })
+}
+
+/// Holds response for /time request
+#[derive(Deserialize, Debug)]
+struct TimeResponse {
+    /// Coordinator timestamp
+    pub ts: CoordinatorTimestamp,
+}
+
+/// Holds response for /lookup request
+#[derive(Deserialize, Debug)]
+struct LookupResponse {
+    /// Host holding the worker id at the requested time
+    #[serde(alias = "host")]
+    pub hostname: String,
+}
+
+/// Difference between local and coordinator time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkewReport {
+    /// Local unix time in seconds
+    pub local_ts: CoordinatorTimestamp,
+    /// Coordinator unix time in seconds
+    pub remote_ts: CoordinatorTimestamp,
+    /// `remote_ts - local_ts`, positive if the coordinator is ahead
+    pub diff: i64,
+}
+
+/// Last skew per coordinator url, with the time it was measured
+static SKEW_CACHE: Lazy<Mutex<HashMap<String, (Instant, SkewReport)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl SkewReport {
+    fn new(local_ts: CoordinatorTimestamp, remote_ts: CoordinatorTimestamp) -> Self {
+        SkewReport {
+            local_ts,
+            remote_ts,
+            diff: (remote_ts as i128 - local_ts as i128) as i64,
+        }
+    }
+}
+
+/// Returns the cached skew of `coordinator_url` if it's younger then `ttl`
+fn cached_skew(coordinator_url: &str, ttl: Duration) -> Option<SkewReport> {
+    let cache = SKEW_CACHE.lock().expect("Couldn't lock skew cache");
+    cache
+        .get(coordinator_url)
+        .filter(|(at, _)| at.elapsed() < ttl)
+        .map(|(_, report)| *report)
+}
+
+fn cache_skew(coordinator_url: &str, report: SkewReport) {
+    SKEW_CACHE
+        .lock()
+        .expect("Couldn't lock skew cache")
+        .insert(coordinator_url.to_string(), (Instant::now(), report));
+}
+
+/// Limits concurrent requests to one coordinator
+#[derive(Debug)]
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Slot of a [Semaphore], given back on drop
+struct Permit<'a>(&'a Semaphore);
+
+impl Semaphore {
+    fn acquire(&self) -> Permit<'_> {
+        let available = self.available.lock().expect("Couldn't lock semaphore");
+        let mut available = self
+            .released
+            .wait_while(available, |n| *n == 0)
+            .expect("Couldn't lock semaphore");
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().expect("Couldn't lock semaphore") += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Request limit per coordinator url, shared by all generators using it
+static REQUEST_LIMITS: Lazy<Mutex<HashMap<String, Arc<Semaphore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the request limit of the coordinator of `config`
+///
+/// The limit is taken from the first config using the coordinator url
+fn request_limit(config: &SnowflakeConfig) -> Arc<Semaphore> {
+    REQUEST_LIMITS
+        .lock()
+        .expect("Couldn't lock request limits")
+        .entry(config.coordinator_url.clone())
+        .or_insert_with(|| {
+            Arc::new(Semaphore {
+                available: Mutex::new(config.max_concurrent_requests.max(1)),
+                released: Condvar::new(),
+            })
+        })
+        .clone()
+}
+
+/// Requests `url` from the coordinator of `config`, waiting while too many requests are in flight
+fn request<T: DeserializeOwned>(config: &SnowflakeConfig, url: &str) -> Result<T, SnowflakeError> {
+    request_optional(config, url)?
+        .ok_or_else(|| SnowflakeError::InvalidResponse("empty response".to_string()))
+}
+
+/// Like [request], but `None` if the coordinator answered 204 No Content
+fn request_optional<T: DeserializeOwned>(
+    config: &SnowflakeConfig,
+    url: &str,
+) -> Result<Option<T>, SnowflakeError> {
+    let url = config.url_rewriter.rewrite(url);
+    limited(config, || fetch_optional(&url, config.request_timeout))
+}
+
+/// Like [request_optional], but outside the request limit of `config`
+///
+/// A long-poll is held open for up to its timeout, so holding a permit meanwhile would starve the
+/// claims and re-verifies of other generators using the coordinator
+#[cfg(not(target_arch = "wasm32"))]
+fn poll_optional<T: DeserializeOwned>(
+    config: &SnowflakeConfig,
+    url: &str,
+) -> Result<Option<T>, SnowflakeError> {
+    fetch_optional(&config.url_rewriter.rewrite(url), config.request_timeout)
+}
+
+/// Runs the request `f` once the request limit of `config` allows it, recording its latency
+pub(crate) fn limited<T>(
+    config: &SnowflakeConfig,
+    f: impl FnOnce() -> Result<T, SnowflakeError>,
+) -> Result<T, SnowflakeError> {
+    let limit = request_limit(config);
+    let _permit = limit.acquire();
+    let start = Instant::now();
+    let response = f();
+    otel::coordinator_latency(start.elapsed());
+    response
+}
+
+/// Progress of the background re-verify task
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReverifyState {
+    /// Time of the last successful re-verify
+    pub last_success: Option<SystemTime>,
+    /// Failed attempts since the last success
+    pub consecutive_failures: u32,
+    /// Time of the next planned attempt
+    pub next_attempt: Option<SystemTime>,
+    /// Error which stopped re-verifying, e.g. [SnowflakeError::LayoutChanged]
+    pub last_error: Option<String>,
+    /// How long before the lease expires it's re-verified, widened by slow re-verifies
+    pub margin: Duration,
+}
+
+/// Re-verify latencies kept to widen the renewal margin
+#[cfg(not(target_arch = "wasm32"))]
+const LATENCY_SAMPLES: usize = 32;
+
+/// Recent re-verify latencies, their p99 widens the renewal margin beyond [PRE_TIME]
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default)]
+struct Latencies(VecDeque<Duration>);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Latencies {
+    fn record(&mut self, latency: Duration) {
+        if self.0.len() == LATENCY_SAMPLES {
+            self.0.pop_front();
+        }
+        self.0.push_back(latency);
+    }
+
+    fn p99(&self) -> Duration {
+        let mut sorted: Vec<_> = self.0.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (sorted.len() * 99).div_ceil(100);
+        rank.checked_sub(1).map_or(Duration::ZERO, |i| sorted[i])
+    }
+
+    /// `secs` to sleep until a re-verify with the base margin, shortened by the p99 latency
+    fn sleep_secs(&self, secs: u64) -> u64 {
+        let p99 = self.p99();
+        secs.saturating_sub(p99.as_secs() + (p99.subsec_nanos() > 0) as u64)
+    }
+}
+
+/// Re-verify state shared with the background task
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SharedReverifyState {
+    state: Arc<Mutex<ReverifyState>>,
+    /// Error which stopped re-verifying, set once
+    stopped: Arc<OnceCell<String>>,
+}
+
+impl SharedReverifyState {
+    /// Copy of the current state
+    pub(crate) fn get(&self) -> ReverifyState {
+        self.state
+            .lock()
+            .expect("Couldn't lock re-verify state")
+            .clone()
+    }
+
+    /// Error which stopped re-verifying, without locking
+    pub(crate) fn stopped(&self) -> Option<&str> {
+        self.stopped.get().map(String::as_str)
+    }
+}
+
+/// Applies `f` to the shared re-verify state
+fn update(state: &SharedReverifyState, f: impl FnOnce(&mut ReverifyState)) {
+    f(&mut state.state.lock().expect("Couldn't lock re-verify state"))
+}
+
+/// Local unix time in seconds
+pub(crate) fn local_ts() -> CoordinatorTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Requests `url` and parses the coordinator response, `None` if it answered 204 No Content
+#[cfg(not(target_arch = "wasm32"))]
+fn fetch_optional<T: DeserializeOwned>(
+    url: &str,
+    timeout: Option<Duration>,
+) -> Result<Option<T>, SnowflakeError> {
+    let mut request = ureq::get(url);
+    if let Some(timeout) = timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request.call().map_err(|e| match e {
+        ureq::Error::Status(status, _) => SnowflakeError::Status(status),
+        ureq::Error::Transport(t) => SnowflakeError::Transport(t.to_string()),
+    })?;
+    if response.status() == 204 {
+        return Ok(None);
+    }
+    let body = response
+        .into_string()
+        .map_err(|e| SnowflakeError::InvalidResponse(e.to_string()))?;
+    serde_json::from_str(&body)
+        .map(Some)
+        .map_err(|e| SnowflakeError::InvalidResponse(e.to_string()))
+}
+
+/// Coordinators can't be reached without blocking io, use [crate::WorkerIdSource::Static]
+#[cfg(target_arch = "wasm32")]
+fn fetch_optional<T: DeserializeOwned>(
+    _url: &str,
+    _timeout: Option<Duration>,
+) -> Result<Option<T>, SnowflakeError> {
+    Err(SnowflakeError::Transport(
+        "coordinators aren't supported on wasm32".to_string(),
+    ))
+}
+
+/// Errors if coordinator and local time differ by more then `max_skew`
+fn check_skew(
+    local_ts: CoordinatorTimestamp,
+    remote_ts: CoordinatorTimestamp,
+    max_skew: Duration,
+) -> Result<(), SnowflakeError> {
+    if (local_ts as i128 - remote_ts as i128).abs() > max_skew.as_secs() as i128 {
+        log::error!("Local TS: {}", local_ts);
+        log::error!("Rev TS: {}", remote_ts);
+        log::error!("Diff: {}", (local_ts as i128 - remote_ts as i128).abs());
+        return Err(SnowflakeError::ClockSkew {
+            local_ts,
+            remote_ts,
+        });
+    }
+    Ok(())
+}
+
+/// Measures the difference between local and coordinator time with a single /time request
+///
+/// Reuses skews measured (or seen during a claim) within `config.skew_cache_ttl`
+pub(crate) fn measure_skew(config: &SnowflakeConfig) -> Result<SkewReport, SnowflakeError> {
+    if let Some(report) = cached_skew(&config.coordinator_url, config.skew_cache_ttl) {
+        return Ok(report);
+    }
+    let tr: TimeResponse = request(config, &format!("{}/time", config.coordinator_url))?;
+    let report = SkewReport::new(local_ts(), tr.ts);
+    cache_skew(&config.coordinator_url, report);
+    Ok(report)
+}
+
+/// Median absolute offset of the coordinator clock over `samples` /time requests, NTP-style
+///
+/// Every sample compares the coordinator time to the local midpoint of its request. Samples
+/// whose round trip took more then twice the median one are dropped as outliers
+pub(crate) fn estimate_clock_offset(
+    config: &SnowflakeConfig,
+    samples: usize,
+) -> Result<Duration, SnowflakeError> {
+    let url = format!("{}/time", config.coordinator_url);
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_nanos() as i128
+    };
+    // (round trip, offset) in nano secs
+    let mut measured = Vec::with_capacity(samples.max(1));
+    for _ in 0..samples.max(1) {
+        let sent = now();
+        let tr: TimeResponse = request(config, &url)?;
+        let received = now();
+        let remote = tr.ts as i128 * 1_000_000_000;
+        measured.push((received - sent, remote - (sent + received) / 2));
+    }
+    let mut round_trips: Vec<_> = measured.iter().map(|(rtt, _)| *rtt).collect();
+    round_trips.sort_unstable();
+    let max_rtt = round_trips[round_trips.len() / 2] * 2;
+    let mut offsets: Vec<_> = measured
+        .iter()
+        .filter(|(rtt, _)| *rtt <= max_rtt)
+        .map(|(_, offset)| *offset)
+        .collect();
+    offsets.sort_unstable();
+    let median = offsets[offsets.len() / 2];
+    Ok(Duration::from_nanos(median.unsigned_abs() as u64))
+}
+
+/// Measures the skew of the coordinator of `config` on a background thread until it's reachable
+///
+/// Logs an error if it exceeds `config.max_skew`
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn spawn_skew_check(config: SnowflakeConfig, result: Arc<OnceCell<SkewReport>>) {
+    thread::spawn(move || {
+        let mut attempt = 0;
+        let report = loop {
+            match measure_skew(&config) {
+                Ok(report) => break report,
+                Err(e) => {
+                    log::warn!("Deferred skew check failed. Attempt: {}: {}", attempt, e);
+                    sleep(config.retry.backoff(attempt));
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        };
+        if let Err(e) = check_skew(report.local_ts, report.remote_ts, config.max_skew) {
+            log::error!("Provisioned worker id generated with excessive skew: {}", e);
+        }
+        let _ = result.set(report);
+    });
+}
+
+/// Looks up the host which held `worker_id` at `at_time`
+pub(crate) fn resolve_worker(
+    config: &SnowflakeConfig,
+    worker_id: WorkerId,
+    at_time: SystemTime,
+) -> Result<String, SnowflakeError> {
+    let at = at_time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let lr: LookupResponse = request(
+        config,
+        &format!("{}/lookup/{}?at={}", config.coordinator_url, worker_id, at),
+    )?;
+    Ok(lr.hostname)
+}
+
+/// Called with a worker id whose lease the coordinator revoked, returns if it stops re-verifying
+pub(crate) type OnRevoked = Arc<dyn Fn(WorkerId) -> bool + Send + Sync>;
+
+/// Returns if the coordinator refused a re-verify because the lease is gone
+#[cfg(not(target_arch = "wasm32"))]
+fn is_revoked(e: &SnowflakeError) -> bool {
+    matches!(e, SnowflakeError::Status(409) | SnowflakeError::Status(410))
+}
+
+/// Claims a worker id from `coordinator` and starts re-verifying it
+///
+/// `timeout` replaces the configured request timeout of the claim, not of later re-verifies
+pub(crate) fn claim(
+    config: &SnowflakeConfig,
+    coordinator: &Arc<dyn Coordinator>,
+    state: &SharedReverifyState,
+    timeout: Option<Duration>,
+    on_revoked: Option<OnRevoked>,
+) -> Result<WorkerId, SnowflakeError> {
+    let coordinator_url = &config.coordinator_url;
+    log::debug!("Coordinator url: {}", coordinator_url);
+    let cr = coordinator.claim(timeout)?;
+
+    let local_ts = local_ts();
+    cache_skew(coordinator_url, SkewReport::new(local_ts, cr.ts));
+    check_skew(local_ts, cr.ts, config.max_skew)?;
+    check_layout(config, &cr)?;
+
+    if cr.re_ts < local_ts {
+        return Err(SnowflakeError::LeaseAlreadyExpired {
+            re_ts: cr.re_ts,
+            local_ts,
+        });
+    }
+    coordinator.accepted(&cr);
+
+    // Attempts to verify PRE_TIME secs before it has to be done
+    let time_to_next_sleep = cr.re_ts.saturating_sub(PRE_TIME + local_ts);
+    update(state, |s| {
+        s.next_attempt = Some(SystemTime::now() + Duration::from_secs(time_to_next_sleep));
+        s.margin = Duration::from_secs(PRE_TIME);
+    });
+    #[cfg(not(target_arch = "wasm32"))]
+    spawn_reverify(
+        config.clone(),
+        coordinator.clone(),
+        cr.id,
+        time_to_next_sleep,
+        state.clone(),
+        on_revoked,
+    );
+    #[cfg(target_arch = "wasm32")]
+    let _ = on_revoked;
+    Ok(cr.id)
+}
+
+/// Re-verifies worker id `id` on a background thread, first after `time_to_next_sleep` seconds,
+/// then as each renewed lease requires
+///
+/// Stops once the lease is revoked if `on_revoked` handles it, retries as for other errors otherwise
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_reverify(
+    config: SnowflakeConfig,
+    coordinator: Arc<dyn Coordinator>,
+    id: WorkerId,
+    time_to_next_sleep: u64,
+    state: SharedReverifyState,
+    on_revoked: Option<OnRevoked>,
+) {
+    thread::spawn(move || {
+        if !wait_for_reverify(&config, id, time_to_next_sleep, &state, on_revoked.as_ref()) {
+            return;
+        }
+        log::info!("re-verifying snowflake worker id");
+        let mut latencies = Latencies::default();
+        loop {
+            let started = Instant::now();
+            let mut verify_response = coordinator.reverify(id);
+            latencies.record(started.elapsed());
+            let mut re_verify = 0;
+            while let Err(e) = &verify_response {
+                otel::reverify_failed();
+                if is_revoked(e) && on_revoked.as_ref().is_some_and(|f| f(id)) {
+                    log::warn!("Lease of worker id {} revoked, stopped re-verifying it", id);
+                    return;
+                }
+                let backoff = config.retry.backoff(re_verify);
+                update(&state, |s| {
+                    s.consecutive_failures += 1;
+                    s.next_attempt = Some(SystemTime::now() + backoff);
+                });
+                if !config.retry.should_retry(re_verify, started.elapsed()) {
+                    return fatal(&config, &state, "Failed to re-verify snowflake worker id !");
+                }
+                log::warn!("re-verifying failed. Attempt: {}", re_verify);
+                re_verify += 1;
+                sleep(backoff);
+                let attempted = Instant::now();
+                verify_response = coordinator.reverify(id);
+                latencies.record(attempted.elapsed());
+            }
+
+            match verify_response {
+                Ok(rev) => {
+                    if rev.id != id {
+                        return fatal(
+                            &config,
+                            &state,
+                            format!("Snowflake worker id changed ! {} -> {}", rev.id, id),
+                        );
+                    }
+
+                    let local_ts = local_ts();
+                    if let Err(e) = check_skew(local_ts, rev.ts, config.max_skew)
+                        .and_then(|_| check_layout(&config, &rev))
+                    {
+                        return fatal(&config, &state, e);
+                    }
+                    // Each renewed lease may come with its own ttl
+                    let next_sleep =
+                        latencies.sleep_secs(rev.re_ts.saturating_sub(PRE_TIME + local_ts));
+                    log::info!("Snowflake re-validated, next: {}", next_sleep);
+                    update(&state, |s| {
+                        let now = SystemTime::now();
+                        s.last_success = Some(now);
+                        s.consecutive_failures = 0;
+                        s.next_attempt = Some(now + Duration::from_secs(next_sleep));
+                        s.margin = Duration::from_secs(PRE_TIME) + latencies.p99();
+                    });
+
+                    if !wait_for_reverify(&config, id, next_sleep, &state, on_revoked.as_ref()) {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    unreachable!("re_verify should stop before coming here !")
+                }
+            }
+        }
+    });
+}
+
+/// Shortest interval between long-polls, so a coordinator answering 204 at once isn't flooded
+#[cfg(not(target_arch = "wasm32"))]
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits `secs` until the next re-verify, long-polling the coordinator if configured
+///
+/// A poll answered with 204 No Content timed out and is re-issued, at most every
+/// [MIN_POLL_INTERVAL] or poll timeout. A poll answered with a lease counts as re-verify and
+/// restarts the wait with the new lease. A revoked lease is passed to `on_revoked` as while
+/// re-verifying
+/// # Returns
+/// * bool - if re-verifying goes on, false once `on_revoked` stopped it
+#[cfg(not(target_arch = "wasm32"))]
+fn wait_for_reverify(
+    config: &SnowflakeConfig,
+    id: WorkerId,
+    secs: u64,
+    state: &SharedReverifyState,
+    on_revoked: Option<&OnRevoked>,
+) -> bool {
+    let timeout = match config.long_poll {
+        Some(timeout) => timeout,
+        None => {
+            sleep(Duration::from_secs(secs));
+            return true;
+        }
+    };
+    let mut deadline = Instant::now() + Duration::from_secs(secs);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        let polled = Instant::now();
+        let url = format!(
+            "{}/poll/{}?timeout_ms={}",
+            config.coordinator_url,
+            id,
+            timeout.min(remaining).as_millis()
+        );
+        let response = poll_optional::<CoordinatorResponse>(config, &url)
+            .and_then(|cr| cr.map(Lease::try_from).transpose());
+        match response {
+            Ok(None) => {
+                log::debug!("Long-poll timed out, re-issuing");
+                let interval = MIN_POLL_INTERVAL.min(timeout).min(remaining);
+                sleep(interval.saturating_sub(polled.elapsed()));
+            }
+            Ok(Some(cr)) => {
+                if cr.id != id {
+                    fatal(
+                        config,
+                        state,
+                        format!("Snowflake worker id changed ! {} -> {}", id, cr.id),
+                    );
+                    return false;
+                }
+                let local_ts = local_ts();
+                if let Err(e) = check_skew(local_ts, cr.ts, config.max_skew)
+                    .and_then(|_| check_layout(config, &cr))
+                {
+                    fatal(config, state, e);
+                    return false;
+                }
+                let next = cr.re_ts.saturating_sub(PRE_TIME + local_ts);
+                log::info!("Coordinator renewed snowflake lease early, next: {}", next);
+                update(state, |s| {
+                    let now = SystemTime::now();
+                    s.last_success = Some(now);
+                    s.consecutive_failures = 0;
+                    s.next_attempt = Some(now + Duration::from_secs(next));
+                });
+                deadline = Instant::now() + Duration::from_secs(next);
+            }
+            Err(e) if is_revoked(&e) && on_revoked.is_some_and(|f| f(id)) => {
+                log::warn!("Lease of worker id {} revoked, stopped re-verifying it", id);
+                return false;
+            }
+            Err(e) => {
+                log::warn!("Long-poll failed, sleeping until re-verify: {}", e);
+                sleep(remaining);
+                return true;
+            }
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::coordinator::{CoordinatorResponse, Lease};
+    use crate::{mock, Generator, Layout, Snowflake, SnowflakeConfig, SnowflakeError, PRE_TIME};
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    pub fn test_measure_skew() {
+        let coordinator = mock::serve(|path| {
+            assert_eq!(path, "/time");
+            (200, format!(r#"{{"ts":{}}}"#, mock::now_secs() - 120))
+        });
+        let report = Snowflake::measure_skew(&SnowflakeConfig::new(&coordinator.url)).unwrap();
+        assert_eq!(
+            report.remote_ts as i64 - report.local_ts as i64,
+            report.diff
+        );
+        assert!((-121..=-119).contains(&report.diff));
+        assert_eq!(coordinator.hits(), 1);
+    }
+
+    #[test]
+    pub fn test_response_aliases() {
+        let cr: CoordinatorResponse =
+            serde_json::from_str(r#"{"worker_id":3,"timestamp":100,"renew_at":200}"#).unwrap();
+        assert_eq!((cr.id, cr.ts, cr.re_ts), (3, 100, Some(200)));
+
+        let cr: CoordinatorResponse =
+            serde_json::from_str(r#"{"id":4,"timestamp":10,"re_ts":20}"#).unwrap();
+        assert_eq!((cr.id, cr.ts, cr.re_ts), (4, 10, Some(20)));
+    }
+
+    #[test]
+    pub fn test_ttl_response() {
+        let cr: CoordinatorResponse =
+            serde_json::from_str(r#"{"id":4,"ts":10,"ttl_seconds":600}"#).unwrap();
+        let lease = Lease::try_from(cr).unwrap();
+        let expected = mock::now_secs() + 600;
+        assert!((expected - 1..=expected).contains(&lease.re_ts));
+
+        let cr: CoordinatorResponse = serde_json::from_str(r#"{"id":4,"ts":10}"#).unwrap();
+        assert!(matches!(
+            Lease::try_from(cr),
+            Err(SnowflakeError::InvalidResponse(_))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_ttl_renewal_scheduling() {
+        let coordinator = mock::serve(|_| {
+            (
+                200,
+                format!(
+                    r#"{{"id":6,"ts":{},"ttl":{}}}"#,
+                    mock::now_secs(),
+                    PRE_TIME + 60
+                ),
+            )
+        });
+        let generator = Generator::new(&coordinator.url);
+        assert_eq!(generator.generate(0).await.worker_id, 6);
+        // Renewed PRE_TIME secs before the TTL runs out
+        let next = generator.reverify_state().next_attempt.unwrap();
+        let wait = next.duration_since(SystemTime::now()).unwrap();
+        assert!((58..=60).contains(&wait.as_secs()), "{:?}", wait);
+    }
+
+    #[test]
+    pub fn test_estimate_clock_offset() {
+        let skews = Mutex::new(vec![10, 9, 100, 10, 11, 10, -50].into_iter());
+        let coordinator = mock::serve(move |_| {
+            let skew = skews.lock().unwrap().next().unwrap_or(10);
+            (
+                200,
+                format!(r#"{{"ts":{}}}"#, mock::now_secs() as i64 + skew),
+            )
+        });
+        let config = SnowflakeConfig::new(&coordinator.url);
+        let offset = Snowflake::estimate_clock_offset(&config, 7).unwrap();
+        assert!(
+            (9..=11).contains(&offset.as_secs()),
+            "offset {:?} isn't the median",
+            offset
+        );
+        assert_eq!(coordinator.hits(), 7);
+    }
+
+    #[test]
+    pub fn test_skew_cache() {
+        let coordinator = mock::serve(|_| (200, format!(r#"{{"ts":{}}}"#, mock::now_secs())));
+        let config = SnowflakeConfig::new(&coordinator.url);
+        let first = Snowflake::measure_skew(&config).unwrap();
+        assert_eq!(Snowflake::measure_skew(&config).unwrap(), first);
+        assert_eq!(coordinator.hits(), 1);
+
+        let uncached = SnowflakeConfig::builder(&coordinator.url)
+            .skew_cache_ttl(Duration::ZERO)
+            .build();
+        Snowflake::measure_skew(&uncached).unwrap();
+        assert_eq!(coordinator.hits(), 2);
+    }
+
+    #[test]
+    pub fn test_resolve_worker() {
+        let coordinator = mock::serve(|path| match path {
+            "/lookup/7?at=1600000000" => (200, r#"{"hostname":"node-3"}"#.to_string()),
+            _ => (404, String::new()),
+        });
+        let config = SnowflakeConfig::new(&coordinator.url);
+        let at = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(Snowflake::resolve_worker(&config, 7, at).unwrap(), "node-3");
+        assert!(matches!(
+            Snowflake::resolve_worker(&config, 8, at),
+            Err(SnowflakeError::Status(404))
+        ));
+    }
+
+    #[test]
+    pub fn test_request_limit() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (current, max) = (in_flight.clone(), peak.clone());
+        let coordinator = mock::serve(move |_| {
+            max.fetch_max(current.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            current.fetch_sub(1, Ordering::SeqCst);
+            (200, format!(r#"{{"ts":{}}}"#, mock::now_secs()))
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .skew_cache_ttl(Duration::ZERO)
+            .max_concurrent_requests(2)
+            .build();
+
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let config = config.clone();
+                thread::spawn(move || Snowflake::measure_skew(&config).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(coordinator.hits(), 6);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    /// Serves leases which have to be re-verified after a second, answering polls with `poll`
+    fn long_poll_coordinator(
+        poll: impl Fn() -> (u16, String) + Send + Sync + 'static,
+    ) -> (mock::MockCoordinator, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let (polls, reverifies) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (poll_count, reverify_count) = (polls.clone(), reverifies.clone());
+        let coordinator = mock::serve(move |path| {
+            if path.starts_with("/poll/5?timeout_ms=") {
+                poll_count.fetch_add(1, Ordering::SeqCst);
+                return poll();
+            }
+            if path.starts_with("/reverify/") {
+                reverify_count.fetch_add(1, Ordering::SeqCst);
+            }
+            let now = mock::now_secs();
+            let body = format!(r#"{{"id":5,"ts":{},"re_ts":{}}}"#, now, now + PRE_TIME + 1);
+            (200, body)
+        });
+        (coordinator, polls, reverifies)
+    }
+
+    #[tokio::test]
+    pub async fn test_long_poll_early() {
+        let notified = AtomicUsize::new(0);
+        let (coordinator, polls, reverifies) = long_poll_coordinator(move || {
+            if notified.fetch_add(1, Ordering::SeqCst) == 0 {
+                return (200, mock::lease(5));
+            }
+            thread::sleep(Duration::from_millis(200));
+            (204, String::new())
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .long_poll(Some(Duration::from_secs(30)))
+            .build();
+        let generator = Generator::with_config(config);
+        generator.generate(0).await;
+
+        thread::sleep(Duration::from_millis(1500));
+        let state = generator.reverify_state();
+        assert!(state.last_success.is_some());
+        assert!(state.next_attempt.unwrap() > SystemTime::now() + Duration::from_secs(3000));
+        assert!(polls.load(Ordering::SeqCst) >= 2);
+        assert_eq!(reverifies.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_long_poll_unlimited() {
+        let (coordinator, polls, _) = long_poll_coordinator(|| {
+            thread::sleep(Duration::from_secs(3));
+            (204, String::new())
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .long_poll(Some(Duration::from_secs(30)))
+            .max_concurrent_requests(2)
+            .build();
+        let pollers: Vec<_> = (0..3)
+            .map(|_| Generator::with_config(config.clone()))
+            .collect();
+        for poller in &pollers {
+            poller.generate(0).await;
+        }
+        thread::sleep(Duration::from_millis(1500));
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+
+        // More polls are open than permits, claims still go through right away
+        let start = Instant::now();
+        Generator::with_config(config).generate(0).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    pub async fn test_long_poll_timeout() {
+        let (coordinator, polls, reverifies) = long_poll_coordinator(|| {
+            thread::sleep(Duration::from_millis(200));
+            (204, String::new())
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .long_poll(Some(Duration::from_millis(200)))
+            .build();
+        let generator = Generator::with_config(config);
+        generator.generate(0).await;
+
+        thread::sleep(Duration::from_millis(1500));
+        assert!(polls.load(Ordering::SeqCst) >= 3);
+        assert!(reverifies.load(Ordering::SeqCst) >= 1);
+        assert!(generator.reverify_state().last_success.is_some());
+    }
+
+    #[tokio::test]
+    pub async fn test_layout_changed() {
+        let layout = r#"{"timestamp_bits":64,"worker_bits":16,"sequence_bits":8,"usage_bits":8"#;
+        let coordinator = mock::serve(move |path| {
+            // Re-verify a second after the claim, reporting another epoch
+            let epoch = if path == "/" { 0 } else { 1_600_000_000 };
+            (
+                200,
+                format!(
+                    r#"{{"id":7,"ts":{},"re_ts":{},"layout":{},"epoch":{}}}}}"#,
+                    mock::now_secs(),
+                    mock::now_secs() + PRE_TIME + 1,
+                    layout,
+                    epoch
+                ),
+            )
+        });
+        let generator = Generator::new(&coordinator.url);
+        assert_eq!(generator.generate(0).await.worker_id, 7);
+        let start = std::time::Instant::now();
+        let error = loop {
+            if let Some(e) = generator.reverify_state().last_error {
+                break e;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no layout change");
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(error.contains("layout"), "{}", error);
+        assert_eq!(generator.reverify_state().last_success, None);
+        assert!(matches!(
+            generator.try_generate(0).await,
+            Err(SnowflakeError::ReverifyStopped(reason)) if reason == error
+        ));
+        assert!(matches!(
+            generator.try_generate_sync(0),
+            Err(SnowflakeError::ReverifyStopped(reason)) if reason == error
+        ));
+        let generator = Arc::new(generator);
+        let best_effort = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            generator.generate_best_effort(0)
+        }));
+        assert!(best_effort.is_err());
+        assert!(!SnowflakeError::LayoutChanged {
+            expected: (&Layout::DEFAULT).into(),
+            reported: (&Layout::DEFAULT).into(),
+        }
+        .is_retryable());
+    }
+
+    #[tokio::test]
+    pub async fn test_regions() {
+        let coordinator = mock::serve(|_| {
+            (
+                200,
+                format!(
+                    r#"{{"id":8,"ts":{},"ttl":3600,"regions":{{"241":"ap-south","242":"ap-east"}}}}"#,
+                    mock::now_secs()
+                ),
+            )
+        });
+        assert_eq!(Snowflake::usage_for_region("ap-east"), None);
+        let generator = Generator::new(&coordinator.url);
+        generator.generate(0).await;
+        assert_eq!(Snowflake::usage_for_region("ap-south"), Some(241));
+        let usage_id = Snowflake::usage_for_region("ap-east").unwrap();
+        let snowflake = generator.generate(usage_id).await;
+        assert_eq!(snowflake.usage_name().as_deref(), Some("ap-east"));
+
+        // Regions of a rejected lease aren't registered
+        let expired = mock::serve(|_| {
+            (
+                200,
+                format!(
+                    r#"{{"id":9,"ts":{},"re_ts":{},"regions":{{"243":"eu-north"}}}}"#,
+                    mock::now_secs(),
+                    mock::now_secs() - 10
+                ),
+            )
+        });
+        assert!(Generator::new(&expired.url).try_generate(0).await.is_err());
+        assert_eq!(Snowflake::usage_for_region("eu-north"), None);
+    }
+
+    #[tokio::test]
+    pub async fn test_url_rewriter() {
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let seen = paths.clone();
+        let coordinator = mock::serve(move |path| {
+            seen.lock().unwrap().push(path.to_string());
+            (200, mock::lease(4))
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .url_rewriter(|url| format!("{}?token=signed", url))
+            .build();
+        let generator = Generator::with_config(config);
+        assert_eq!(generator.generate(0).await.worker_id, 4);
+        assert_eq!(
+            paths.lock().unwrap().first().map(String::as_str),
+            Some("/?token=signed")
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_reverify_ttl() {
+        let reverified = Arc::new(AtomicUsize::new(0));
+        let reverify_count = reverified.clone();
+        let coordinator = mock::serve(move |path| {
+            let now = mock::now_secs();
+            if path.starts_with("/reverify/") {
+                reverify_count.fetch_add(1, Ordering::SeqCst);
+                return (200, mock::lease(5));
+            }
+            let body = format!(r#"{{"id":5,"ts":{},"re_ts":{}}}"#, now, now + PRE_TIME + 1);
+            (200, body)
+        });
+        let generator = Generator::new(&coordinator.url);
+        generator.generate(0).await;
+
+        thread::sleep(Duration::from_millis(2500));
+        // The hour long lease of the re-verify applies, not the second of the claim
+        assert_eq!(reverified.load(Ordering::SeqCst), 1);
+        let state = generator.reverify_state();
+        assert!(state.next_attempt.unwrap() > SystemTime::now() + Duration::from_secs(3000));
+    }
+
+    #[tokio::test]
+    pub async fn test_long_poll_floor() {
+        let (coordinator, polls, _) = long_poll_coordinator(|| (204, String::new()));
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .long_poll(Some(Duration::from_secs(30)))
+            .build();
+        let generator = Generator::with_config(config);
+        generator.generate(0).await;
+
+        // Immediate 204s are re-issued once a second, not in a busy loop
+        thread::sleep(Duration::from_millis(500));
+        assert!(polls.load(Ordering::SeqCst) <= 2);
+    }
+}
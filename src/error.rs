@@ -0,0 +1,452 @@
+use crate::{CoordinatorTimestamp, LeaseLayout, SequenceId, UsageId, WorkerId};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+use std::{fmt, io};
+
+/// Errors returned by fallible snowflake operations
+#[derive(Debug)]
+pub enum SnowflakeError {
+    /// Another process on this host already holds the worker id
+    WorkerIdInUse {
+        /// Conflicting worker id
+        worker_id: WorkerId,
+        /// Pid of the process holding the host lock, 0 if it couldn't be read
+        pid: u32,
+    },
+    /// Local io operation failed
+    Io(io::Error),
+    /// Coordinator couldn't be reached
+    Transport(String),
+    /// Coordinator answered with a non-200 status
+    Status(u16),
+    /// Coordinator response couldn't be parsed
+    InvalidResponse(String),
+    /// Coordinator url is unusable
+    InvalidUrl(String),
+    /// Layout is unusable
+    InvalidLayout(String),
+    /// Accepted worker id range is empty or doesn't fit the layout
+    InvalidWorkerIdRange {
+        /// First accepted worker id
+        start: WorkerId,
+        /// Last accepted worker id
+        end: WorkerId,
+        /// Biggest worker id of the layout
+        max: WorkerId,
+    },
+    /// Sequence id range is empty or starts beyond the layout
+    InvalidSequenceRange {
+        /// First sequence id of the range
+        start: SequenceId,
+        /// Last sequence id of the range
+        end: SequenceId,
+        /// Biggest sequence id of the layout
+        max: SequenceId,
+    },
+    /// Retry policy is unusable
+    InvalidRetryPolicy(String),
+    /// Rate limit is unusable
+    InvalidRateLimit(String),
+    /// Worker id isn't accepted by the config or doesn't fit the layout
+    WorkerIdOutOfRange {
+        /// Rejected worker id
+        worker_id: WorkerId,
+        /// Smallest accepted worker id
+        min: WorkerId,
+        /// Biggest accepted worker id
+        max: WorkerId,
+    },
+    /// Sequence of the current timestamp is exhausted
+    SequenceExhausted,
+    /// [crate::IdPool] has no snowflake buffered
+    PoolEmpty,
+    /// Worker id hasn't been claimed yet, synchronous generation doesn't claim it
+    WorkerIdUninitialized,
+    /// Clock went backwards
+    ClockRegressed {
+        /// Nano secs the clock went back
+        by: u128,
+    },
+    /// Coordinator handed out a lease which already has to be re-verified
+    LeaseAlreadyExpired {
+        /// Re-verify time of the lease, coordinator unix time in seconds
+        re_ts: CoordinatorTimestamp,
+        /// Local unix time in seconds
+        local_ts: CoordinatorTimestamp,
+    },
+    /// Snowflake is older then the accepted maximum age
+    TooOld {
+        /// Age of the snowflake
+        age: Duration,
+        /// Accepted maximum age
+        max_age: Duration,
+    },
+    /// Coordinator reported a layout other then the configured one
+    LayoutChanged {
+        /// Configured layout
+        expected: LeaseLayout,
+        /// Layout reported by the coordinator
+        reported: LeaseLayout,
+    },
+    /// [crate::Snowflake::self_test] found a violation
+    SelfTestFailed(String),
+    /// Pod name doesn't end with a StatefulSet ordinal, see [crate::WorkerIdSource::from_pod_name]
+    InvalidPodName(String),
+    /// Snowflake comes from a worker id outside of the allowed set, see
+    /// [crate::Snowflake::validate_worker_in_set]
+    UnexpectedWorker(WorkerId),
+    /// Usage id isn't registered, see [crate::Snowflake::new_with_usage_validated]
+    UnknownUsage(UsageId),
+    /// No usage id is named after the region, see [crate::Snowflake::new_for_region]
+    UnknownRegion(String),
+    /// Snowflake fields don't fit the layout
+    Parse(ParseError),
+    /// Generator is draining and doesn't accept new generations, see
+    /// [crate::Snowflake::begin_drain]
+    Draining,
+    /// Global generator is already initialized with another config, see [crate::Snowflake::init],
+    /// or a cached one of [crate::Snowflake::try_new_with_config]
+    AlreadyInitialized,
+    /// Sequence range can't be split into that many affinity stripes, see
+    /// [crate::SnowflakeConfig::affinity_stripes]
+    InvalidAffinityStripes(u8),
+    /// [crate::SnowflakeConfig::long_poll] timeout is zero
+    InvalidLongPoll,
+    /// [crate::SnowflakeConfig::salt] doesn't fit the sequence range or layout
+    InvalidSalt(String),
+    /// Re-verifying the worker id failed for good, see [crate::ReverifyFailurePolicy]
+    ReverifyStopped(String),
+    /// Batch failed part way, see [crate::Generator::generate_fallible_batch]
+    BatchFailed {
+        /// Snowflakes generated before the failure, they're discarded
+        generated: usize,
+        /// Error failing the batch
+        source: Box<SnowflakeError>,
+    },
+    /// Coordinator time and local time differ by more then the allowed skew
+    ClockSkew {
+        /// Local unix time in seconds
+        local_ts: CoordinatorTimestamp,
+        /// Coordinator unix time in seconds
+        remote_ts: CoordinatorTimestamp,
+    },
+}
+
+impl SnowflakeError {
+    /// Returns if retrying the failed operation may succeed
+    ///
+    /// Transport failures, timeouts, 5xx and 429 responses and transient clock or sequence states are
+    /// retryable, config errors, other statuses and invalid data aren't
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            SnowflakeError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+            ),
+            SnowflakeError::Status(status) => *status == 429 || (500..600).contains(status),
+            SnowflakeError::BatchFailed { source, .. } => source.is_retryable(),
+            SnowflakeError::Transport(_)
+            | SnowflakeError::SequenceExhausted
+            | SnowflakeError::PoolEmpty
+            | SnowflakeError::WorkerIdUninitialized
+            | SnowflakeError::ClockRegressed { .. }
+            | SnowflakeError::LeaseAlreadyExpired { .. }
+            | SnowflakeError::ClockSkew { .. } => true,
+            SnowflakeError::WorkerIdInUse { .. }
+            | SnowflakeError::InvalidResponse(_)
+            | SnowflakeError::InvalidUrl(_)
+            | SnowflakeError::InvalidLayout(_)
+            | SnowflakeError::InvalidWorkerIdRange { .. }
+            | SnowflakeError::InvalidSequenceRange { .. }
+            | SnowflakeError::InvalidRetryPolicy(_)
+            | SnowflakeError::InvalidRateLimit(_)
+            | SnowflakeError::WorkerIdOutOfRange { .. }
+            | SnowflakeError::TooOld { .. }
+            | SnowflakeError::SelfTestFailed(_)
+            | SnowflakeError::LayoutChanged { .. }
+            | SnowflakeError::InvalidPodName(_)
+            | SnowflakeError::UnexpectedWorker(_)
+            | SnowflakeError::UnknownUsage(_)
+            | SnowflakeError::UnknownRegion(_)
+            | SnowflakeError::Parse(_)
+            | SnowflakeError::Draining
+            | SnowflakeError::AlreadyInitialized
+            | SnowflakeError::InvalidAffinityStripes(_)
+            | SnowflakeError::InvalidLongPoll
+            | SnowflakeError::InvalidSalt(_)
+            | SnowflakeError::ReverifyStopped(_) => false,
+        }
+    }
+}
+
+impl Display for SnowflakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SnowflakeError::WorkerIdInUse { worker_id, pid } => write!(
+                f,
+                "Worker id {} is already in use on this host by pid {}",
+                worker_id, pid
+            ),
+            SnowflakeError::Io(e) => write!(f, "Io error: {}", e),
+            SnowflakeError::Transport(e) => write!(f, "Coordinator unreachable: {}", e),
+            SnowflakeError::Status(status) => {
+                write!(f, "Coordinator gave non-200 response: {}", status)
+            }
+            SnowflakeError::InvalidResponse(e) => {
+                write!(f, "Couldn't parse coordinator response: {}", e)
+            }
+            SnowflakeError::InvalidUrl(reason) => write!(f, "Invalid coordinator url: {}", reason),
+            SnowflakeError::InvalidLayout(reason) => write!(f, "Invalid layout: {}", reason),
+            SnowflakeError::InvalidWorkerIdRange { start, end, max } => write!(
+                f,
+                "Worker id range {}..={} is empty or exceeds the layout maximum {}",
+                start, end, max
+            ),
+            SnowflakeError::InvalidSequenceRange { start, end, max } => write!(
+                f,
+                "Sequence id range {}..={} is empty or starts beyond the layout maximum {}",
+                start, end, max
+            ),
+            SnowflakeError::InvalidRetryPolicy(reason) => {
+                write!(f, "Invalid retry policy: {}", reason)
+            }
+            SnowflakeError::InvalidRateLimit(reason) => write!(f, "Invalid rate limit: {}", reason),
+            SnowflakeError::WorkerIdOutOfRange {
+                worker_id,
+                min,
+                max,
+            } => write!(
+                f,
+                "Worker id {} is outside of the accepted range {}..={}",
+                worker_id, min, max
+            ),
+            SnowflakeError::SequenceExhausted => {
+                write!(f, "Sequence of the current timestamp is exhausted")
+            }
+            SnowflakeError::PoolEmpty => write!(f, "Id pool is empty"),
+            SnowflakeError::WorkerIdUninitialized => write!(f, "Worker id isn't claimed yet"),
+            SnowflakeError::ClockRegressed { by } => {
+                write!(f, "Clock went backwards by {} ns", by)
+            }
+            SnowflakeError::LeaseAlreadyExpired { re_ts, local_ts } => write!(
+                f,
+                "Coordinator re-verify time {} is smaller then local time {}",
+                re_ts, local_ts
+            ),
+            SnowflakeError::TooOld { age, max_age } => {
+                write!(f, "Snowflake is {:?} old, maximum is {:?}", age, max_age)
+            }
+            SnowflakeError::SelfTestFailed(reason) => write!(f, "Self-test failed: {}", reason),
+            SnowflakeError::LayoutChanged { expected, reported } => write!(
+                f,
+                "Coordinator reported layout {:?}, configured is {:?}",
+                reported, expected
+            ),
+            SnowflakeError::InvalidPodName(name) => {
+                write!(f, "Pod name {:?} doesn't end with an ordinal", name)
+            }
+            SnowflakeError::UnexpectedWorker(id) => {
+                write!(f, "Snowflake comes from unexpected worker id {}", id)
+            }
+            SnowflakeError::UnknownUsage(id) => write!(f, "Usage id {} isn't registered", id),
+            SnowflakeError::UnknownRegion(name) => {
+                write!(f, "No usage id is registered for region {:?}", name)
+            }
+            SnowflakeError::Parse(e) => write!(f, "Invalid snowflake: {}", e),
+            SnowflakeError::Draining => write!(f, "Generator is draining"),
+            SnowflakeError::InvalidLongPoll => write!(f, "Long-poll timeout has to be above zero"),
+            SnowflakeError::InvalidSalt(reason) => write!(f, "Invalid salt: {}", reason),
+            SnowflakeError::ReverifyStopped(reason) => {
+                write!(f, "Worker id isn't re-verified anymore: {}", reason)
+            }
+            SnowflakeError::InvalidAffinityStripes(stripes) => write!(
+                f,
+                "Sequence range can't be split into {} affinity stripes",
+                stripes
+            ),
+            SnowflakeError::AlreadyInitialized => {
+                write!(
+                    f,
+                    "Global generator is already initialized with another config"
+                )
+            }
+            SnowflakeError::BatchFailed { generated, source } => {
+                write!(f, "Batch failed after {} snowflakes: {}", generated, source)
+            }
+            SnowflakeError::ClockSkew {
+                local_ts,
+                remote_ts,
+            } => write!(
+                f,
+                "Coordinator time {} and local time {} differ by more then the allowed skew",
+                remote_ts, local_ts
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnowflakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnowflakeError::Io(e) => Some(e),
+            SnowflakeError::Parse(e) => Some(e),
+            SnowflakeError::BatchFailed { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnowflakeError {
+    fn from(e: io::Error) -> Self {
+        SnowflakeError::Io(e)
+    }
+}
+
+impl From<ParseError> for SnowflakeError {
+    fn from(e: ParseError) -> Self {
+        SnowflakeError::Parse(e)
+    }
+}
+
+/// Errors returned when parsing a snowflake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// Input doesn't have the expected length
+    InvalidLength {
+        /// Expected length
+        expected: usize,
+        /// Actual length
+        actual: usize,
+    },
+    /// Input contains a character the encoding doesn't use, e.g. a non-hex one
+    InvalidCharacter,
+    /// Field value doesn't fit the width of the layout
+    FieldOverflow {
+        /// Name of the overflowing field
+        field: &'static str,
+    },
+    /// Numeric value is wider then the packed snowflake, see [crate::Snowflake::from_numeric_str]
+    Overflow,
+    /// Timestamp falls between two ticks of the layout resolution
+    UnalignedTimestamp,
+    /// Input doesn't start with the expected prefix, see [crate::Snowflake::parse_prefixed]
+    PrefixMismatch,
+    /// Decoded column disagrees with the hex column, see [crate::Snowflake::from_csv_row]
+    ColumnMismatch {
+        /// Name of the disagreeing column
+        column: &'static str,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidLength { expected, actual } => write!(
+                f,
+                "Expected snowflake of length {}, got {}",
+                expected, actual
+            ),
+            ParseError::InvalidCharacter => write!(f, "Snowflake contains an invalid character"),
+            ParseError::FieldOverflow { field } => {
+                write!(f, "Snowflake {} doesn't fit the layout", field)
+            }
+            ParseError::Overflow => write!(f, "Snowflake value is too large"),
+            ParseError::UnalignedTimestamp => {
+                write!(f, "Snowflake timestamp isn't a tick of the layout")
+            }
+            ParseError::PrefixMismatch => write!(f, "Snowflake doesn't have the expected prefix"),
+            ParseError::ColumnMismatch { column } => {
+                write!(f, "Column {} doesn't match the snowflake", column)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ParseError, SnowflakeError};
+    use std::io;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_is_retryable() {
+        let retryable = [
+            SnowflakeError::Io(io::Error::from(io::ErrorKind::TimedOut)),
+            SnowflakeError::Transport("connection refused".to_string()),
+            SnowflakeError::Status(503),
+            SnowflakeError::Status(429),
+            SnowflakeError::SequenceExhausted,
+            SnowflakeError::PoolEmpty,
+            SnowflakeError::WorkerIdUninitialized,
+            SnowflakeError::ClockRegressed { by: 1 },
+            SnowflakeError::LeaseAlreadyExpired {
+                re_ts: 1,
+                local_ts: 2,
+            },
+            SnowflakeError::ClockSkew {
+                local_ts: 1,
+                remote_ts: 100,
+            },
+            SnowflakeError::BatchFailed {
+                generated: 2,
+                source: Box::new(SnowflakeError::ClockRegressed { by: 1 }),
+            },
+        ];
+        for e in &retryable {
+            assert!(e.is_retryable(), "{:?}", e);
+        }
+        let permanent = [
+            SnowflakeError::WorkerIdInUse {
+                worker_id: 1,
+                pid: 1,
+            },
+            SnowflakeError::Io(io::Error::from(io::ErrorKind::PermissionDenied)),
+            SnowflakeError::Status(404),
+            SnowflakeError::InvalidResponse("eof".to_string()),
+            SnowflakeError::InvalidUrl("no scheme".to_string()),
+            SnowflakeError::InvalidLayout("empty".to_string()),
+            SnowflakeError::InvalidWorkerIdRange {
+                start: 2,
+                end: 1,
+                max: 10,
+            },
+            SnowflakeError::InvalidSequenceRange {
+                start: 2,
+                end: 1,
+                max: 255,
+            },
+            SnowflakeError::InvalidRetryPolicy("zero attempts".to_string()),
+            SnowflakeError::InvalidRateLimit("zero burst".to_string()),
+            SnowflakeError::WorkerIdOutOfRange {
+                worker_id: 11,
+                min: 0,
+                max: 10,
+            },
+            SnowflakeError::TooOld {
+                age: Duration::from_secs(2),
+                max_age: Duration::from_secs(1),
+            },
+            SnowflakeError::SelfTestFailed("duplicate".to_string()),
+            SnowflakeError::InvalidPodName("web".to_string()),
+            SnowflakeError::UnexpectedWorker(3),
+            SnowflakeError::UnknownUsage(4),
+            SnowflakeError::UnknownRegion("mars".to_string()),
+            SnowflakeError::Parse(ParseError::InvalidCharacter),
+            SnowflakeError::Draining,
+            SnowflakeError::AlreadyInitialized,
+            SnowflakeError::InvalidAffinityStripes(0),
+            SnowflakeError::InvalidLongPoll,
+            SnowflakeError::InvalidSalt("striped".to_string()),
+            SnowflakeError::ReverifyStopped("layout changed".to_string()),
+            SnowflakeError::BatchFailed {
+                generated: 0,
+                source: Box::new(SnowflakeError::InvalidUrl("no scheme".to_string())),
+            },
+        ];
+        for e in &permanent {
+            assert!(!e.is_retryable(), "{:?}", e);
+        }
+    }
+}
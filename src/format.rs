@@ -0,0 +1,199 @@
+//! Pluggable string encodings of snowflakes
+
+use crate::{ParseError, Snowflake};
+use once_cell::sync::Lazy;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+/// Bits of the [crate::Layout::DEFAULT] packing
+const PACKED_BITS: u32 = 96;
+/// Digits of [Base62Formatter], a packed snowflake needs 17
+const BASE62_LEN: usize = 17;
+/// Alphabet of [Base62Formatter], in ascii order so strings sort like the snowflakes
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Instance of the default formatter, shared so default configs are equal
+static DEFAULT: Lazy<SharedFormatter> = Lazy::new(|| SharedFormatter::new(HexFormatter));
+
+/// String encoding of snowflakes, see [crate::SnowflakeConfig::formatter]
+///
+/// `parse` has to accept everything `format` returns
+pub trait IdFormatter: Debug + Send + Sync {
+    /// Encodes `snowflake`
+    fn format(&self, snowflake: &Snowflake) -> String;
+    /// Decodes a snowflake encoded by [IdFormatter::format]
+    fn parse(&self, s: &str) -> Result<Snowflake, ParseError>;
+}
+
+/// Hex representation of [std::fmt::Display] and [std::str::FromStr], the default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HexFormatter;
+
+impl IdFormatter for HexFormatter {
+    fn format(&self, snowflake: &Snowflake) -> String {
+        snowflake.canonical()
+    }
+
+    fn parse(&self, s: &str) -> Result<Snowflake, ParseError> {
+        s.parse()
+    }
+}
+
+/// Dash grouped hex of [Snowflake::to_grouped_hex]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GroupedHexFormatter;
+
+impl IdFormatter for GroupedHexFormatter {
+    fn format(&self, snowflake: &Snowflake) -> String {
+        snowflake.to_grouped_hex()
+    }
+
+    fn parse(&self, s: &str) -> Result<Snowflake, ParseError> {
+        Snowflake::from_grouped_hex(s)
+    }
+}
+
+/// 17 base62 digits of [Snowflake::to_u128], shorter then hex and still ordered like the snowflakes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Base62Formatter;
+
+impl IdFormatter for Base62Formatter {
+    fn format(&self, snowflake: &Snowflake) -> String {
+        let mut v = snowflake.to_u128();
+        let mut digits = [b'0'; BASE62_LEN];
+        for digit in digits.iter_mut().rev() {
+            *digit = BASE62[(v % 62) as usize];
+            v /= 62;
+        }
+        digits.iter().map(|d| *d as char).collect()
+    }
+
+    fn parse(&self, s: &str) -> Result<Snowflake, ParseError> {
+        if s.len() != BASE62_LEN {
+            return Err(ParseError::InvalidLength {
+                expected: BASE62_LEN,
+                actual: s.len(),
+            });
+        }
+        let v = s.bytes().try_fold(0u128, |v, b| {
+            let digit = BASE62
+                .iter()
+                .position(|d| *d == b)
+                .ok_or(ParseError::InvalidCharacter)?;
+            Ok(v * 62 + digit as u128)
+        })?;
+        if v >> PACKED_BITS != 0 {
+            return Err(ParseError::FieldOverflow { field: "timestamp" });
+        }
+        Ok(Snowflake::from_u128(v))
+    }
+}
+
+/// [IdFormatter] shared by configs, equal if it's the same instance
+#[derive(Clone)]
+pub struct SharedFormatter(pub Arc<dyn IdFormatter>);
+
+impl SharedFormatter {
+    /// Shares `formatter`
+    pub fn new(formatter: impl IdFormatter + 'static) -> Self {
+        SharedFormatter(Arc::new(formatter))
+    }
+}
+
+impl Default for SharedFormatter {
+    fn default() -> Self {
+        DEFAULT.clone()
+    }
+}
+
+impl Debug for SharedFormatter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq for SharedFormatter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedFormatter {}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::format::{Base62Formatter, GroupedHexFormatter, HexFormatter, IdFormatter};
+    use crate::{Generator, ParseError, Snowflake, SnowflakeConfig, WorkerIdSource};
+
+    /// Hex with a `sf_` prefix
+    #[derive(Debug)]
+    struct Prefixed;
+
+    impl IdFormatter for Prefixed {
+        fn format(&self, snowflake: &Snowflake) -> String {
+            format!("sf_{}", snowflake)
+        }
+
+        fn parse(&self, s: &str) -> Result<Snowflake, ParseError> {
+            s.strip_prefix("sf_")
+                .ok_or(ParseError::InvalidCharacter)?
+                .parse()
+        }
+    }
+
+    #[test]
+    pub fn test_formatters() {
+        let snowflake = Snowflake::from_parts(1_688_000_000_000_000_000, 7, 3, 2).unwrap();
+        let formatters: [&dyn IdFormatter; 4] = [
+            &HexFormatter,
+            &GroupedHexFormatter,
+            &Base62Formatter,
+            &Prefixed,
+        ];
+        for formatter in formatters {
+            let s = formatter.format(&snowflake);
+            assert_eq!(formatter.parse(&s), Ok(snowflake), "{:?}", formatter);
+        }
+        let s = Prefixed.format(&snowflake);
+        assert_eq!(s, format!("sf_{}", snowflake.canonical()));
+        assert_eq!(Prefixed.parse(&s[3..]), Err(ParseError::InvalidCharacter));
+        let generator = Generator::builder()
+            .config(SnowflakeConfig::builder("").formatter(Prefixed).build())
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        assert_eq!(generator.format(&snowflake), s);
+        assert_eq!(generator.parse(&s), Ok(snowflake));
+    }
+
+    #[test]
+    pub fn test_base62() {
+        let min = Snowflake::from_u128(0);
+        let max = Snowflake::from_u128(u128::MAX >> 32);
+        assert_eq!(Base62Formatter.format(&min), "00000000000000000");
+        let a = Snowflake::from_u128(12_345);
+        let b = Snowflake::from_u128(1 << 90);
+        let mut encoded = [max, b, a, min].map(|s| Base62Formatter.format(&s));
+        encoded.sort();
+        assert_eq!(
+            encoded.map(|s| Base62Formatter.parse(&s).unwrap()),
+            [min, a, b, max]
+        );
+        assert_eq!(
+            Base62Formatter.parse("zzzzzzzzzzzzzzzzz"),
+            Err(ParseError::FieldOverflow { field: "timestamp" })
+        );
+        assert_eq!(
+            Base62Formatter.parse("0000000000000000-"),
+            Err(ParseError::InvalidCharacter)
+        );
+        assert_eq!(
+            Base62Formatter.parse("0"),
+            Err(ParseError::InvalidLength {
+                expected: 17,
+                actual: 1
+            })
+        );
+    }
+}
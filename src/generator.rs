@@ -0,0 +1,3895 @@
+//! Per-instance snowflake generation
+
+use crate::clock::{Clock, SystemClock};
+use crate::coordinator::{Coordinator, OnRevoked, ReverifyState, SharedReverifyState, SkewReport};
+use crate::diagnostics::{ConfigSummary, Diagnostics};
+use crate::host_lock::HostLock;
+use crate::idempotency::KeyCache;
+use crate::layout::Layout;
+use crate::meter::RateMeter;
+use crate::rate_limit::TokenBucket;
+use crate::retry::RetryPolicy;
+use crate::{
+    config, coordinator, otel, FlushPolicy, NanoTimestamp, ParseError, SequenceId, Snowflake,
+    SnowflakeConfig, SnowflakeError, UsageId, WorkerId,
+};
+use futures_core::Stream;
+use once_cell::sync::{Lazy, OnceCell};
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(not(target_arch = "wasm32"))]
+use {
+    std::collections::hash_map::RandomState,
+    std::hash::{BuildHasher, Hasher},
+    std::ops::Deref,
+    std::thread,
+    std::time::Instant,
+};
+
+/// Snowflakes generated per lock in long running loops
+const CHUNK_SIZE: usize = 256;
+/// Immediate re-claims after the coordinator handed out an already expired lease
+const EXPIRED_LEASE_RECLAIMS: u32 = 3;
+/// Nano secs synchronous generation waits for the next tick before giving up
+const SYNC_TIMEOUT: NanoTimestamp = 1_000_000;
+/// Minimal nano secs between two clock regression warnings
+const REGRESSION_LOG_INTERVAL: NanoTimestamp = 10_000_000_000;
+/// Nano secs the generation rate is averaged over for [SnowflakeConfig::capacity_warning]
+const RATE_WINDOW: NanoTimestamp = 1_000_000_000;
+/// Value of [Generator::promoted] while no standby worker id is promoted
+const NOT_PROMOTED: u32 = u32::MAX;
+/// Minimal nano secs between two capacity warnings
+const CAPACITY_LOG_INTERVAL: NanoTimestamp = 60_000_000_000;
+
+/// Last snowflake issued by a generator with [SnowflakeConfig::strict_monotonic]
+static LAST_ISSUED: Lazy<Mutex<Option<Snowflake>>> = Lazy::new(|| Mutex::new(None));
+
+thread_local! {
+    /// Usage id set by [with_usage_scope] on this thread
+    static USAGE_SCOPE: Cell<Option<UsageId>> = const { Cell::new(None) };
+}
+
+/// Runs `f` with `usage_id` as ambient usage id of this thread, restoring the previous one after
+pub(crate) fn with_usage_scope<R>(usage_id: UsageId, f: impl FnOnce() -> R) -> R {
+    /// Restores the previous scope, even if `f` panics
+    struct Restore(Option<UsageId>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            USAGE_SCOPE.with(|scope| scope.set(self.0));
+        }
+    }
+
+    let _restore = Restore(USAGE_SCOPE.with(|scope| scope.replace(Some(usage_id))));
+    f()
+}
+
+/// Ambient usage id of this thread, 0 outside of a scope
+pub(crate) fn scoped_usage() -> UsageId {
+    USAGE_SCOPE.with(|scope| scope.get()).unwrap_or(0)
+}
+
+/// Where a generator gets its worker id from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerIdSource {
+    /// Claimed from the configured coordinator and re-verified in the background
+    Coordinator,
+    /// Fixed worker id, no coordinator is involved
+    Static(WorkerId),
+    /// Worker id provisioned out of band, e.g. offline-first deployments
+    ///
+    /// Generation starts right away, the skew against the configured coordinator is measured in
+    /// the background once it's reachable, see [Generator::deferred_skew]
+    Provisioned(WorkerId),
+}
+
+impl WorkerIdSource {
+    /// Uses the ordinal of a StatefulSet pod name like `web-7` as static worker id
+    ///
+    /// The worker id is checked against the accepted range when the generator is built
+    pub fn from_pod_name(pod_name: &str) -> Result<Self, SnowflakeError> {
+        let invalid = || SnowflakeError::InvalidPodName(pod_name.to_string());
+        let (set, ordinal) = pod_name.trim().rsplit_once('-').ok_or_else(invalid)?;
+        if set.is_empty() || ordinal.is_empty() || !ordinal.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        Ok(WorkerIdSource::Static(
+            ordinal.parse().map_err(|_| invalid())?,
+        ))
+    }
+
+    /// Like [WorkerIdSource::from_pod_name] with the pod name of ```POD_NAME```
+    pub fn from_pod_env() -> Result<Self, SnowflakeError> {
+        let pod_name = env::var("POD_NAME")
+            .map_err(|_| SnowflakeError::InvalidPodName("POD_NAME not set".to_string()))?;
+        WorkerIdSource::from_pod_name(&pod_name)
+    }
+
+    /// Like [WorkerIdSource::from_pod_name] with the pod name of a downward-API file
+    pub fn from_pod_name_file(path: impl AsRef<Path>) -> Result<Self, SnowflakeError> {
+        WorkerIdSource::from_pod_name(&fs::read_to_string(path)?)
+    }
+}
+
+/// What to do when the sequence of the current timestamp is exhausted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SameInstant {
+    /// Wait until the clock reaches the next tick
+    #[default]
+    Wait,
+    /// Continue on the next tick right away, running ahead of the clock during bursts
+    Borrow,
+    /// Return [SnowflakeError::SequenceExhausted]
+    Error,
+}
+
+/// Generates snowflakes with a worker id claimed from a coordinator
+///
+/// The worker id is claimed on first use and re-verified on a background thread afterwards
+#[derive(Debug)]
+pub struct Generator {
+    config: SnowflakeConfig,
+    source: WorkerIdSource,
+    worker_id: OnceCell<WorkerId>,
+    host_lock: OnceCell<HostLock>,
+    /// Salt xor-ed into generated snowflakes, 0 if disabled
+    salt: u16,
+    /// Shared by all members of a [GeneratorGroup]
+    state: Arc<Mutex<State>>,
+    /// Sequence sub-ranges of [SnowflakeConfig::affinity_stripes] after the first, which `state`
+    /// covers
+    stripes: Vec<Mutex<State>>,
+    reverify: SharedReverifyState,
+    coordinator: Arc<dyn Coordinator>,
+    /// Skew measured in the background for [WorkerIdSource::Provisioned]
+    deferred_skew: Arc<OnceCell<SkewReport>>,
+    /// If [Generator::generate_best_effort] started a background claim
+    #[cfg(not(target_arch = "wasm32"))]
+    claim_started: AtomicBool,
+    clock: Arc<dyn Clock>,
+    /// Generation rate limit, if configured
+    limiter: Option<Mutex<TokenBucket>>,
+    idempotent: Mutex<KeyCache>,
+    on_resolved: OnResolved,
+    /// Generation rate of [Generator::current_rate]
+    rate: RateMeter,
+    /// Generations in progress, see [Generator::begin_drain]
+    drain: Drain,
+    /// Spare worker id of [SnowflakeConfig::standby], until it's promoted
+    standby: Arc<Mutex<Option<WorkerId>>>,
+    /// Re-verify state of the spare worker id, reported once it's promoted
+    standby_reverify: SharedReverifyState,
+    /// Host lock of the spare worker id
+    standby_lock: OnceCell<HostLock>,
+    /// Standby worker id replacing the claimed one after a revocation, or [NOT_PROMOTED]
+    promoted: Arc<AtomicU32>,
+}
+
+/// Callback of [GeneratorBuilder::on_worker_id_resolved]
+type ResolvedHook = Box<dyn FnOnce(WorkerId) + Send>;
+
+/// Hook waiting for the worker id to be resolved
+#[derive(Default)]
+struct OnResolved {
+    hook: Mutex<Option<ResolvedHook>>,
+    /// If a hook is waiting, so resolved worker ids don't lock
+    pending: AtomicBool,
+}
+
+impl fmt::Debug for OnResolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OnResolved")
+            .field("pending", &self.pending.load(AtomicOrdering::Relaxed))
+            .finish()
+    }
+}
+
+impl OnResolved {
+    /// Waits for the worker id with `hook`, calling it right away if `resolved` is known
+    fn set(&self, hook: ResolvedHook, resolved: impl FnOnce() -> Option<WorkerId>) {
+        let mut waiting = self.hook.lock().expect("Couldn't lock worker id hook");
+        match resolved() {
+            Some(id) => {
+                drop(waiting);
+                hook(id);
+            }
+            None => {
+                *waiting = Some(hook);
+                self.pending.store(true, AtomicOrdering::Release);
+            }
+        }
+    }
+
+    /// Calls the waiting hook with the resolved worker id
+    fn fire(&self, id: WorkerId) {
+        if !self.pending.load(AtomicOrdering::Acquire) {
+            return;
+        }
+        let hook = {
+            let mut waiting = self.hook.lock().expect("Couldn't lock worker id hook");
+            self.pending.store(false, AtomicOrdering::Release);
+            waiting.take()
+        };
+        if let Some(hook) = hook {
+            hook(id);
+        }
+    }
+}
+
+/// Timestamp and sequence of the last generated snowflake
+#[derive(Debug)]
+struct State {
+    prev_ts: NanoTimestamp,
+    sequence_id: SequenceId,
+    layout: Layout,
+    /// See [SnowflakeConfig::sequence_range], capped at the layout maximum
+    sequence_ids: RangeInclusive<SequenceId>,
+    same_instant: SameInstant,
+    /// Last clock reading, to notice the clock going backwards
+    last_clock: NanoTimestamp,
+    /// Times the clock went backwards
+    regressions: u64,
+    /// Clock reading of the last regression warning
+    last_regression_log: Option<NanoTimestamp>,
+    /// See [SnowflakeConfig::capacity_warning]
+    capacity_warning: Option<u8>,
+    /// Clock reading at the start of the current rate window
+    window_start: Option<NanoTimestamp>,
+    /// Snowflakes generated in the current rate window
+    window_ids: u128,
+    /// Rate windows above the capacity warning threshold
+    capacity_warnings: u64,
+    /// Clock reading of the last capacity warning
+    last_capacity_log: Option<NanoTimestamp>,
+}
+
+/// Builds a [Generator]
+pub struct GeneratorBuilder {
+    config: SnowflakeConfig,
+    source: WorkerIdSource,
+    coordinator: Option<Arc<dyn Coordinator>>,
+    clock: Arc<dyn Clock>,
+    same_instant: SameInstant,
+    on_resolved: Option<ResolvedHook>,
+}
+
+impl fmt::Debug for GeneratorBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeneratorBuilder")
+            .field("config", &self.config)
+            .field("source", &self.source)
+            .field("coordinator", &self.coordinator)
+            .field("clock", &self.clock)
+            .field("same_instant", &self.same_instant)
+            .field("on_resolved", &self.on_resolved.is_some())
+            .finish()
+    }
+}
+
+impl GeneratorBuilder {
+    /// Sets the config, defaults to [SnowflakeConfig::default]
+    pub fn config(mut self, config: SnowflakeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets where the worker id comes from, defaults to [WorkerIdSource::Coordinator]
+    pub fn worker_id_source(mut self, source: WorkerIdSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Sets the time source, defaults to [SystemClock]
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the protocol of [WorkerIdSource::Coordinator], defaults to the one of the url scheme
+    pub fn coordinator(mut self, coordinator: Arc<dyn Coordinator>) -> Self {
+        self.coordinator = Some(coordinator);
+        self
+    }
+
+    /// Sets the bit layout, keeping the configured epoch
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.config.layout = Layout {
+            epoch: self.config.layout.epoch,
+            ..layout
+        };
+        self
+    }
+
+    /// Sets the start of the timestamp field
+    pub fn epoch(mut self, epoch: SystemTime) -> Self {
+        self.config.layout = self.config.layout.with_epoch(epoch);
+        self
+    }
+
+    /// Sets what happens once the sequence of a timestamp is exhausted, defaults to [SameInstant::Wait]
+    pub fn same_instant(mut self, same_instant: SameInstant) -> Self {
+        self.same_instant = same_instant;
+        self
+    }
+
+    /// Calls `hook` once the worker id is resolved, e.g. to register in service discovery
+    ///
+    /// Fires exactly once, even if many callers trigger the claim concurrently
+    pub fn on_worker_id_resolved(mut self, hook: impl FnOnce(WorkerId) + Send + 'static) -> Self {
+        self.on_resolved = Some(Box::new(hook));
+        self
+    }
+
+    /// Returns the generator, errors if the layout is invalid or the static worker id doesn't fit it
+    pub fn build(self) -> Result<Generator, SnowflakeError> {
+        self.config.layout.validate()?;
+        config::check_sequence_range(&self.config)?;
+        config::check_salt(&self.config)?;
+        config::check_priority_bit(&self.config)?;
+        if let WorkerIdSource::Static(id) | WorkerIdSource::Provisioned(id) = self.source {
+            check_worker_id(id, &self.config)?;
+        }
+        let generator = Generator::with_config(self.config).with_clock(self.clock);
+        generator
+            .state
+            .lock()
+            .expect("Couldn't lock generator state")
+            .same_instant = self.same_instant;
+        for stripe in &generator.stripes {
+            stripe
+                .lock()
+                .expect("Couldn't lock generator state")
+                .same_instant = self.same_instant;
+        }
+        if let Some(hook) = self.on_resolved {
+            generator.on_resolved.set(hook, || None);
+        }
+        Ok(Generator {
+            source: self.source,
+            coordinator: self.coordinator.unwrap_or(generator.coordinator.clone()),
+            ..generator
+        })
+    }
+}
+
+impl Generator {
+    /// Returns a builder for a generator
+    pub fn builder() -> GeneratorBuilder {
+        GeneratorBuilder {
+            config: SnowflakeConfig::default(),
+            source: WorkerIdSource::Coordinator,
+            coordinator: None,
+            clock: Arc::new(SystemClock),
+            same_instant: SameInstant::default(),
+            on_resolved: None,
+        }
+    }
+
+    /// Creates a generator claiming its worker id from `coordinator_url`
+    pub fn new(coordinator_url: impl Into<String>) -> Self {
+        Generator::with_config(SnowflakeConfig::new(coordinator_url))
+    }
+
+    /// Creates a generator using `config`
+    pub fn with_config(config: SnowflakeConfig) -> Self {
+        let new_state = |sequence_ids: RangeInclusive<SequenceId>| State {
+            prev_ts: 0,
+            sequence_id: *sequence_ids.start(),
+            layout: config.layout,
+            sequence_ids,
+            same_instant: SameInstant::default(),
+            last_clock: 0,
+            regressions: 0,
+            last_regression_log: None,
+            capacity_warning: config.capacity_warning,
+            window_start: None,
+            window_ids: 0,
+            capacity_warnings: 0,
+            last_capacity_log: None,
+        };
+        let mut stripes = config::sequence_stripes(&config).into_iter().map(new_state);
+        let state = stripes.next().expect("Sequence range has no stripe");
+        let stripes = stripes.map(Mutex::new).collect();
+        Generator {
+            salt: config.salt.unwrap_or(0),
+            limiter: config.rate_limit.map(|l| Mutex::new(TokenBucket::new(l))),
+            idempotent: Mutex::new(KeyCache::new(config.idempotency)),
+            on_resolved: OnResolved::default(),
+            rate: RateMeter::default(),
+            drain: Drain::default(),
+            standby: Arc::default(),
+            standby_reverify: SharedReverifyState::default(),
+            standby_lock: OnceCell::new(),
+            promoted: Arc::new(AtomicU32::new(NOT_PROMOTED)),
+            coordinator: coordinator::connect(&config),
+            deferred_skew: Arc::default(),
+            #[cfg(not(target_arch = "wasm32"))]
+            claim_started: AtomicBool::new(false),
+            config,
+            source: WorkerIdSource::Coordinator,
+            worker_id: OnceCell::new(),
+            host_lock: OnceCell::new(),
+            state: Arc::new(Mutex::new(state)),
+            stripes,
+            reverify: SharedReverifyState::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Replaces the time source, defaults to [SystemClock]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Current time of the generator clock
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn now_nanos(&self) -> NanoTimestamp {
+        self.clock.now_nanos()
+    }
+
+    /// Config the generator was created with
+    pub(crate) fn config(&self) -> &SnowflakeConfig {
+        &self.config
+    }
+
+    /// Layout of generated snowflakes
+    pub fn layout(&self) -> &Layout {
+        &self.config.layout
+    }
+
+    /// Returns the progress of the background re-verify task
+    pub fn reverify_state(&self) -> ReverifyState {
+        self.active_reverify().get()
+    }
+
+    /// Snowflakes left in the current tick before generation has to wait for the clock
+    ///
+    /// Reads the clock without generating, a full tick saturates at [SequenceId::MAX]
+    pub fn remaining_sequence(&self) -> SequenceId {
+        let state = self.state.lock().expect("Couldn't lock generator state");
+        let (first, last) = (*state.sequence_ids.start(), *state.sequence_ids.end());
+        if state.layout.truncate(self.clock.now_nanos()) > state.prev_ts {
+            (last - first).saturating_add(1)
+        } else {
+            last - state.sequence_id
+        }
+    }
+
+    /// Skew measured in the background for [WorkerIdSource::Provisioned], `None` until measured
+    ///
+    /// A skew beyond [SnowflakeConfig::max_skew] is logged as error, snowflakes generated before
+    /// may not be ordered correctly against other workers
+    pub fn deferred_skew(&self) -> Option<SkewReport> {
+        self.deferred_skew.get().copied()
+    }
+
+    /// Times the clock went backwards while generating, a sign of a misbehaving NTP
+    ///
+    /// Shared by all members of a [GeneratorGroup]
+    pub fn clock_regressions(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("Couldn't lock generator state")
+            .regressions
+    }
+
+    /// Seconds the generation rate exceeded [SnowflakeConfig::capacity_warning]
+    pub fn capacity_warnings(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("Couldn't lock generator state")
+            .capacity_warnings
+    }
+
+    /// Snapshot of the generator state for support bundles, secrets of the config are redacted
+    pub fn diagnostics(&self) -> Diagnostics {
+        let (last_timestamp, sequence_id, clock_regressions, capacity_warnings) = {
+            let state = self.state.lock().expect("Couldn't lock generator state");
+            (
+                state.prev_ts,
+                state.sequence_id,
+                state.regressions,
+                state.capacity_warnings,
+            )
+        };
+        Diagnostics {
+            worker_id: self.claimed_id(),
+            last_timestamp,
+            sequence_id,
+            reverify: self.reverify_state(),
+            clock_regressions,
+            capacity_warnings,
+            config: ConfigSummary::new(&self.config),
+        }
+    }
+
+    /// Rejects new generations with [SnowflakeError::Draining], ones in progress still finish
+    ///
+    /// For rolling deploys, see [Generator::await_drained] before handing the worker id off
+    pub fn begin_drain(&self) {
+        self.drain.draining.store(true, AtomicOrdering::SeqCst);
+    }
+
+    /// Resolves once no generation is in progress anymore
+    ///
+    /// Only stays resolved after [Generator::begin_drain], as new generations are admitted before
+    pub fn await_drained(&self) -> impl Future<Output = ()> + '_ {
+        std::future::poll_fn(move |cx| self.drain.poll_drained(cx))
+    }
+
+    /// Snowflakes generated per second, exponentially weighted over about the last 5 secs
+    ///
+    /// Counting costs one atomic add per generation, reading folds the counts in
+    pub fn current_rate(&self) -> f64 {
+        self.rate.rate(self.clock.now_nanos())
+    }
+
+    /// Encodes `snowflake` with [SnowflakeConfig::formatter]
+    pub fn format(&self, snowflake: &Snowflake) -> String {
+        self.config.formatter.0.format(snowflake)
+    }
+
+    /// Decodes a snowflake encoded by [Generator::format]
+    pub fn parse(&self, s: &str) -> Result<Snowflake, ParseError> {
+        self.config.formatter.0.parse(s)
+    }
+
+    /// Generates a new snowflake, panics if it fails
+    pub async fn generate(&self, usage_id: UsageId) -> Snowflake {
+        match self.try_generate(usage_id).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Generates a new snowflake into `out`, e.g. a slot of an object pool, panics if it fails
+    pub async fn generate_into(&self, usage_id: UsageId, out: &mut Snowflake) {
+        *out = self.generate(usage_id).await;
+    }
+
+    /// Generates a new snowflake
+    pub async fn try_generate(&self, usage_id: UsageId) -> Result<Snowflake, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        self.next(worker_id, usage_id)
+    }
+
+    /// Generates a new snowflake along with how it was generated, panics if it fails
+    pub async fn generate_with_metadata(
+        &self,
+        usage_id: UsageId,
+    ) -> (Snowflake, GenerationMetadata) {
+        match self.try_generate_with_metadata(usage_id).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_with_metadata], but returns errors instead of panicking
+    pub async fn try_generate_with_metadata(
+        &self,
+        usage_id: UsageId,
+    ) -> Result<(Snowflake, GenerationMetadata), SnowflakeError> {
+        self.next_with(self.worker_id()?, usage_id, &self.state, |state| {
+            let prev_ts = state.prev_ts;
+            let waited = state.advance(&*self.clock)?;
+            Ok(GenerationMetadata {
+                waited,
+                sequence_id: state.sequence_id,
+                timestamp_advanced: state.prev_ts != prev_ts,
+            })
+        })
+    }
+
+    /// Generates a new snowflake using at most `max_seq` sequence ids per timestamp, panics if it
+    /// fails
+    ///
+    /// Waits for the clock once the sub-cap is hit, even if the layout allows more. A cap of 0
+    /// counts as 1. Other generate methods still use the full sequence range
+    pub async fn generate_bounded_sequence(
+        &self,
+        usage_id: UsageId,
+        max_seq: SequenceId,
+    ) -> Snowflake {
+        match self.try_generate_bounded_sequence(usage_id, max_seq).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_bounded_sequence], but returns errors instead of panicking
+    pub async fn try_generate_bounded_sequence(
+        &self,
+        usage_id: UsageId,
+        max_seq: SequenceId,
+    ) -> Result<Snowflake, SnowflakeError> {
+        self.next_with(self.worker_id()?, usage_id, &self.state, |state| {
+            state.advance_bounded(&*self.clock, max_seq)
+        })
+        .map(|(snowflake, _)| snowflake)
+    }
+
+    /// Generates a new snowflake from the sequence stripe `cpu_hint` maps to, panics if it fails
+    ///
+    /// Each of the [SnowflakeConfig::affinity_stripes] counts its own disjoint sequence sub-range
+    /// under its own lock, so callers passing e.g. their core index rarely contend. Snowflakes stay
+    /// unique, but are only ordered within a stripe. The first stripe is shared with the other
+    /// generate methods, with a single stripe it's the same as [Generator::generate]
+    pub async fn generate_with_affinity(&self, usage_id: UsageId, cpu_hint: usize) -> Snowflake {
+        match self.try_generate_with_affinity(usage_id, cpu_hint).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_with_affinity], but returns errors instead of panicking
+    pub async fn try_generate_with_affinity(
+        &self,
+        usage_id: UsageId,
+        cpu_hint: usize,
+    ) -> Result<Snowflake, SnowflakeError> {
+        let stripe = match cpu_hint % (self.stripes.len() + 1) {
+            0 => &*self.state,
+            i => &self.stripes[i - 1],
+        };
+        self.next_with(self.worker_id()?, usage_id, stripe, |state| {
+            state.advance(&*self.clock)
+        })
+        .map(|(snowflake, _)| snowflake)
+    }
+
+    /// Packs `timestamp` and `sequence_id` of an external sequencer with the claimed worker id
+    ///
+    /// Bypasses the clock and sequence counter, so uniqueness is up to the caller: a
+    /// timestamp/sequence pair must not repeat for the worker id, and mixing with the other
+    /// generate methods can collide. Not salted, errors if a value doesn't fit the layout or
+    /// `timestamp` isn't a tick of its resolution, as it would be truncated to another timestamp
+    pub async fn generate_with_sequence(
+        &self,
+        usage_id: UsageId,
+        timestamp: NanoTimestamp,
+        sequence_id: SequenceId,
+    ) -> Result<Snowflake, SnowflakeError> {
+        let snowflake = Snowflake {
+            timestamp,
+            worker_id: self.worker_id()?,
+            sequence_id,
+            usage_id,
+        };
+        self.layout().try_pack(&snowflake)?;
+        if self.layout().truncate(timestamp) != timestamp {
+            return Err(ParseError::UnalignedTimestamp.into());
+        }
+        let _in_flight = self.admit(1)?;
+        self.generated(1, self.clock.now_nanos());
+        Ok(snowflake)
+    }
+
+    /// Generates a new snowflake, flagged by [SnowflakeConfig::priority_bit] if `high_priority`
+    ///
+    /// The flag is set after salting, so it's read from the snowflake as generated. Panics if
+    /// `usage_id` overlaps the priority bit, the layout has no usage field or generation fails
+    pub async fn generate_with_priority(
+        &self,
+        usage_id: UsageId,
+        high_priority: bool,
+    ) -> Snowflake {
+        let mask = self.config.priority_mask();
+        assert!(mask != 0, "Layout has no usage bit for the priority flag");
+        assert!(
+            usage_id & mask == 0,
+            "Usage id {} overlaps the priority bit",
+            usage_id
+        );
+        let mut snowflake = self.generate(usage_id).await;
+        snowflake.usage_id &= !mask;
+        if high_priority {
+            snowflake.usage_id |= mask;
+        }
+        snowflake
+    }
+
+    /// Returns if `snowflake` is flagged by [SnowflakeConfig::priority_bit]
+    pub fn is_high_priority(&self, snowflake: &Snowflake) -> bool {
+        snowflake.usage_id & self.config.priority_mask() != 0
+    }
+
+    /// Generates a new snowflake, using `ts_hint` instead of reading the clock
+    ///
+    /// For hot loops where the caller already has a current timestamp (nano secs since unix epoch).
+    /// The clock is read if the hint is smaller then the last reading or its tick is exhausted
+    pub async fn generate_with_ts_hint(
+        &self,
+        usage_id: UsageId,
+        ts_hint: NanoTimestamp,
+    ) -> Snowflake {
+        match self.try_generate_with_ts_hint(usage_id, ts_hint).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_with_ts_hint], but returns errors instead of panicking
+    pub async fn try_generate_with_ts_hint(
+        &self,
+        usage_id: UsageId,
+        ts_hint: NanoTimestamp,
+    ) -> Result<Snowflake, SnowflakeError> {
+        self.next_with(self.worker_id()?, usage_id, &self.state, |state| {
+            state.advance_with_hint(&*self.clock, ts_hint)
+        })
+        .map(|(snowflake, _)| snowflake)
+    }
+
+    /// Generates a new snowflake at least `min_interval` after the previous one of this generator
+    ///
+    /// Waits for the clock if necessary, shaping the timestamps instead of only the rate like
+    /// [SnowflakeConfig::rate_limit], e.g. for evenly spaced test data
+    pub async fn generate_throttled(&self, usage_id: UsageId, min_interval: Duration) -> Snowflake {
+        match self.try_generate_throttled(usage_id, min_interval).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_throttled], but returns errors instead of panicking
+    pub async fn try_generate_throttled(
+        &self,
+        usage_id: UsageId,
+        min_interval: Duration,
+    ) -> Result<Snowflake, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        // Waits with the state unlocked, other generations may go on meanwhile
+        loop {
+            let wait = self.throttle_wait(&self.state, min_interval);
+            if wait.is_zero() {
+                break;
+            }
+            pause(wait);
+        }
+        self.next_with(worker_id, usage_id, &self.state, |state| {
+            // Only a generation racing in since is waited for with the state locked
+            while !state.wait_before(&*self.clock, min_interval).is_zero() {
+                pause(Duration::from_micros(10));
+            }
+            state.advance(&*self.clock)
+        })
+        .map(|(snowflake, _)| snowflake)
+    }
+
+    /// Time until `min_interval` passed since the last snowflake of `stripe`
+    fn throttle_wait(&self, stripe: &Mutex<State>, min_interval: Duration) -> Duration {
+        stripe
+            .lock()
+            .expect("Couldn't lock generator state")
+            .wait_before(&*self.clock, min_interval)
+    }
+
+    /// Generates a new snowflake, or the one already generated for `key`
+    ///
+    /// The first call with a key mints a snowflake, later ones return it until the key expires or
+    /// gets evicted, see [SnowflakeConfig::idempotency]. The usage id of later calls is ignored.
+    /// Concurrent first calls all return the snowflake cached first
+    pub async fn generate_idempotent(&self, usage_id: UsageId, key: &str) -> Snowflake {
+        let now = self.clock.now_nanos();
+        let cached = self
+            .idempotent
+            .lock()
+            .expect("Couldn't lock idempotency cache")
+            .get(key, now);
+        if let Some(snowflake) = cached {
+            return snowflake;
+        }
+        // Generated unlocked, a snowflake of a concurrent call for the key wins
+        let snowflake = match self.worker_id().and_then(|id| self.next(id, usage_id)) {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        };
+        let mut cache = self
+            .idempotent
+            .lock()
+            .expect("Couldn't lock idempotency cache");
+        if let Some(existing) = cache.get(key, now) {
+            return existing;
+        }
+        cache.insert(key, snowflake, now);
+        snowflake
+    }
+
+    /// Generates a new snowflake right away, never waiting for the coordinator
+    ///
+    /// Until the worker id is claimed, a process-random ephemeral worker id of the accepted range is
+    /// used and the snowflake is marked provisional, while the claim runs in the background.
+    /// Provisional snowflakes may collide with those of other workers, use them only where
+    /// uniqueness isn't critical, e.g. fire-and-forget logging. Panics once re-verify of the
+    /// claimed worker id stopped
+    #[cfg(not(target_arch = "wasm32"))]
+    #[track_caller]
+    pub fn generate_best_effort(self: &Arc<Self>, usage_id: UsageId) -> FallbackSnowflake {
+        Generator::best_effort(self.clone(), usage_id)
+    }
+
+    /// [Generator::generate_best_effort] for any handle the background claim can keep
+    #[cfg(not(target_arch = "wasm32"))]
+    #[track_caller]
+    pub(crate) fn best_effort<G>(generator: G, usage_id: UsageId) -> FallbackSnowflake
+    where
+        G: Deref<Target = Generator> + Clone + Send + 'static,
+    {
+        let claimed = match generator.source {
+            WorkerIdSource::Coordinator => match generator.worker_id.get() {
+                Some(id) => match generator.active_id(*id) {
+                    Ok(id) => Some(id),
+                    Err(e) => panic!("Couldn't generate snowflake: {}", e),
+                },
+                None => None,
+            },
+            WorkerIdSource::Static(_) | WorkerIdSource::Provisioned(_) => {
+                generator.worker_id().ok()
+            }
+        };
+        let (worker_id, provisional) = match claimed {
+            Some(id) => (id, false),
+            None => {
+                if !generator.claim_started.swap(true, AtomicOrdering::SeqCst) {
+                    let claiming = generator.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = claiming.worker_id() {
+                            log::warn!("Background worker id claim failed: {}", e);
+                            claiming.claim_started.store(false, AtomicOrdering::SeqCst);
+                        }
+                    });
+                }
+                (generator.ephemeral_worker_id(), true)
+            }
+        };
+        match generator.next(worker_id, usage_id) {
+            Ok(snowflake) => FallbackSnowflake {
+                snowflake,
+                provisional,
+            },
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Process-random worker id within the accepted range
+    #[cfg(not(target_arch = "wasm32"))]
+    fn ephemeral_worker_id(&self) -> WorkerId {
+        static RANDOM: Lazy<u64> = Lazy::new(|| RandomState::new().build_hasher().finish());
+        let min = *self.config.worker_id_range.start() as u64;
+        let max =
+            (*self.config.worker_id_range.end()).min(self.config.layout.max_worker_id()) as u64;
+        (min + *RANDOM % (max.saturating_sub(min) + 1)) as WorkerId
+    }
+
+    /// Generates a new snowflake, appending its bytes to `writer` before returning it
+    ///
+    /// Bytes as of [Snowflake::to_bytes_with] the layout of this generator. For
+    /// write-ahead logs of issued snowflakes, flushed according to [SnowflakeConfig::flush_policy].
+    /// If writing fails the snowflake isn't returned, its sequence slot stays unused
+    pub async fn generate_and_persist(
+        &self,
+        usage_id: UsageId,
+        writer: &mut impl Write,
+    ) -> Result<Snowflake, SnowflakeError> {
+        let snowflake = self.try_generate(usage_id).await?;
+        writer.write_all(&snowflake.to_bytes_with(self.layout()))?;
+        if self.config.flush_policy == FlushPolicy::Always {
+            writer.flush()?;
+        }
+        Ok(snowflake)
+    }
+
+    /// Generates a new snowflake and inserts it into `set`, debug builds assert it's new
+    ///
+    /// Catches collisions in tests and simulations where they happen instead of afterwards
+    #[cfg(feature = "testing")]
+    pub async fn generate_unique_in(
+        &self,
+        usage_id: UsageId,
+        set: &mut std::collections::HashSet<Snowflake>,
+    ) -> Snowflake {
+        let snowflake = self.generate(usage_id).await;
+        let inserted = set.insert(snowflake);
+        debug_assert!(inserted, "Duplicate snowflake {:?}", snowflake);
+        snowflake
+    }
+
+    /// Generates `iterations` snowflakes, erroring if they aren't strictly increasing or the clock
+    /// went backwards meanwhile
+    ///
+    /// Meant to catch a misconfigured generator or broken clock before serving traffic
+    pub async fn self_test(&self, iterations: usize) -> Result<(), SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        let regressions = self.clock_regressions();
+        let mut prev: Option<Snowflake> = None;
+        for _ in 0..iterations {
+            let snowflake = self.next(worker_id, 0)?.unsalted(self.salt);
+            if let Some(prev) = prev {
+                if snowflake == prev {
+                    return Err(SnowflakeError::SelfTestFailed(format!(
+                        "duplicate snowflake {}",
+                        snowflake
+                    )));
+                }
+                if snowflake < prev {
+                    return Err(SnowflakeError::SelfTestFailed(format!(
+                        "snowflake {} is smaller then its predecessor {}",
+                        snowflake, prev
+                    )));
+                }
+            }
+            prev = Some(snowflake);
+        }
+        let regressions = self.clock_regressions() - regressions;
+        if regressions > 0 {
+            return Err(SnowflakeError::SelfTestFailed(format!(
+                "clock went backwards {} times",
+                regressions
+            )));
+        }
+        Ok(())
+    }
+
+    /// Generates a new snowflake with the ambient usage id of [crate::Snowflake::with_usage_scope]
+    ///
+    /// The usage id is read when called, so the future can be awaited outside of the scope
+    pub fn generate_scoped(&self) -> impl Future<Output = Snowflake> + '_ {
+        self.generate(scoped_usage())
+    }
+
+    /// Generates `n` snowflakes under a single lock with the ambient usage id, see
+    /// [Generator::generate_scoped]
+    pub fn generate_scoped_batch(&self, n: usize) -> impl Future<Output = Vec<Snowflake>> + '_ {
+        self.generate_batch(scoped_usage(), n)
+    }
+
+    /// Generates a new snowflake, recording the generation as event under `parent`
+    #[cfg(feature = "tracing")]
+    pub async fn generate_with_span(&self, usage_id: UsageId, parent: &tracing::Span) -> Snowflake {
+        let snowflake = self.generate(usage_id).await;
+        tracing::debug!(
+            parent: parent,
+            snowflake = %snowflake,
+            usage_id,
+            "generated snowflake"
+        );
+        snowflake
+    }
+
+    /// Generates a new snowflake, emitting an info event with it and `tags` as `key=value` pairs
+    ///
+    /// Saves a separate log call at generation sites, the tags are recorded as one `tags` field
+    #[cfg(feature = "tracing")]
+    pub async fn generate_and_tag(&self, usage_id: UsageId, tags: &[(&str, &str)]) -> Snowflake {
+        let snowflake = self.generate(usage_id).await;
+        tracing::info!(
+            snowflake = %snowflake,
+            usage_id,
+            tags = %Tags(tags),
+            "generated snowflake"
+        );
+        snowflake
+    }
+
+    /// Generates a new snowflake without async, panics at the call site if it fails
+    #[track_caller]
+    pub fn generate_sync(&self, usage_id: UsageId) -> Snowflake {
+        match self.try_generate_sync(usage_id) {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflake: {}", e),
+        }
+    }
+
+    /// Generates a new snowflake without async
+    ///
+    /// Doesn't claim a coordinator worker id, but errors if it isn't claimed yet or its re-verify
+    /// stopped. Errors as well if the clock went backwards or the next tick doesn't come within a
+    /// milli sec
+    pub fn try_generate_sync(&self, usage_id: UsageId) -> Result<Snowflake, SnowflakeError> {
+        let worker_id = match self.source {
+            WorkerIdSource::Coordinator => match self.worker_id.get() {
+                Some(id) => self.active_id(*id)?,
+                None => return Err(SnowflakeError::WorkerIdUninitialized),
+            },
+            WorkerIdSource::Static(_) | WorkerIdSource::Provisioned(_) => self.worker_id()?,
+        };
+        let _in_flight = self.admit(1)?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        state.advance_sync(&*self.clock)?;
+        self.generated(1, state.last_clock);
+        Ok(self.issue(&state, worker_id, usage_id))
+    }
+
+    /// Generates a new snowflake, sending on `tx` if it had to wait for the clock
+    ///
+    /// A wait means the sequence of the current nano sec is exhausted, a sign of overload
+    pub async fn generate_with_backpressure(
+        &self,
+        usage_id: UsageId,
+        tx: &Sender<()>,
+    ) -> Result<Snowflake, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        let _in_flight = self.admit(1)?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        if state.advance(&*self.clock)? {
+            // Nobody listening isn't a reason to fail generation
+            let _ = tx.send(());
+        }
+        self.generated(1, state.last_clock);
+        Ok(self.issue(&state, worker_id, usage_id))
+    }
+
+    /// Generates a new snowflake, retrying the worker id claim according to `policy`
+    ///
+    /// Only retries errors which may go away, see [SnowflakeError::is_retryable]
+    pub async fn generate_with_retry(
+        &self,
+        usage_id: UsageId,
+        policy: &RetryPolicy,
+    ) -> Result<Snowflake, SnowflakeError> {
+        self.retrying(usage_id, policy, SnowflakeError::is_retryable)
+            .await
+    }
+
+    /// Generates a new snowflake, re-measuring the clock skew if the claim failed on it
+    ///
+    /// For flaky NTP environments, where a skew at bootstrap may be a momentary blip. Retries
+    /// according to [SnowflakeConfig::skew_retry], other errors are returned right away
+    pub async fn generate_retrying_on_skew(
+        &self,
+        usage_id: UsageId,
+    ) -> Result<Snowflake, SnowflakeError> {
+        self.retrying(usage_id, &self.config.skew_retry, |e| {
+            matches!(e, SnowflakeError::ClockSkew { .. })
+        })
+        .await
+    }
+
+    /// Generates a new snowflake, retrying errors matching `retry` according to `policy`
+    async fn retrying(
+        &self,
+        usage_id: UsageId,
+        policy: &RetryPolicy,
+        retry: impl Fn(&SnowflakeError) -> bool,
+    ) -> Result<Snowflake, SnowflakeError> {
+        // Timed by the generator clock, as std::time::Instant isn't available everywhere
+        let started = self.clock.now_nanos();
+        let mut attempt = 0;
+        loop {
+            match self.try_generate(usage_id).await {
+                Ok(v) => return Ok(v),
+                Err(e)
+                    if retry(&e)
+                        && policy.should_retry(
+                            attempt,
+                            Duration::from_nanos(
+                                self.clock.now_nanos().saturating_sub(started) as u64
+                            ),
+                        ) =>
+                {
+                    log::warn!("Claiming worker id failed. Attempt: {}: {}", attempt, e);
+                    pause(policy.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Generates a new snowflake, falling back to `fallback` as worker id if none is claimed in time
+    ///
+    /// For latency critical paths during coordinator blips. The claim is given `deadline` as request
+    /// timeout, waiting for a claim of another caller isn't bounded. Snowflakes with the fallback
+    /// worker id are marked provisional, `fallback` should be reserved for this
+    pub async fn generate_with_deadline_and_fallback(
+        &self,
+        usage_id: UsageId,
+        deadline: Duration,
+        fallback: WorkerId,
+    ) -> Result<FallbackSnowflake, SnowflakeError> {
+        let (worker_id, provisional) = match self.worker_id_within(Some(deadline)) {
+            Ok(id) => (id, false),
+            Err(e) => {
+                log::warn!("Using fallback worker id {}: {}", fallback, e);
+                check_worker_id(fallback, &self.config)?;
+                (fallback, true)
+            }
+        };
+        Ok(FallbackSnowflake {
+            snowflake: self.next(worker_id, usage_id)?,
+            provisional,
+        })
+    }
+
+    /// Generates a new snowflake along with how close it came to `deadline`, for latency SLOs
+    ///
+    /// The claim is given `deadline` as request timeout like
+    /// [Generator::generate_with_deadline_and_fallback]. Rate limiting and waiting for the clock
+    /// aren't bounded, a late snowflake is still returned and reported as exceeding the deadline
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn generate_with_deadline_metadata(
+        &self,
+        usage_id: UsageId,
+        deadline: Duration,
+    ) -> Result<(Snowflake, DeadlineMetadata), SnowflakeError> {
+        let start = Instant::now();
+        let worker_id = self.worker_id_within(Some(deadline))?;
+        let snowflake = self.next(worker_id, usage_id)?;
+        let elapsed = start.elapsed();
+        Ok((
+            snowflake,
+            DeadlineMetadata {
+                elapsed,
+                remaining: deadline.saturating_sub(elapsed),
+                exceeded: elapsed.saturating_sub(deadline),
+            },
+        ))
+    }
+
+    /// Returns the worker id, claiming it if needed
+    pub(crate) fn worker_id(&self) -> Result<WorkerId, SnowflakeError> {
+        self.worker_id_within(None)
+    }
+
+    /// Returns the worker id, claiming it with request timeout `timeout` if needed
+    ///
+    /// Errors once re-verifying the worker id stopped for good
+    fn worker_id_within(&self, timeout: Option<Duration>) -> Result<WorkerId, SnowflakeError> {
+        let id = self
+            .worker_id
+            .get_or_try_init(|| {
+                let id = match self.source {
+                    WorkerIdSource::Coordinator => self.claim(timeout)?,
+                    WorkerIdSource::Static(id) => id,
+                    WorkerIdSource::Provisioned(id) => {
+                        #[cfg(not(target_arch = "wasm32"))]
+                        coordinator::spawn_skew_check(
+                            self.config.clone(),
+                            self.deferred_skew.clone(),
+                        );
+                        id
+                    }
+                };
+                check_worker_id(id, &self.config)?;
+                if host_lock_enabled() {
+                    let lock = HostLock::acquire(id).map_err(|e| {
+                        log::error!("Refusing to use worker id {}: {}", id, e);
+                        e
+                    })?;
+                    let _ = self.host_lock.set(lock);
+                }
+                Ok::<_, SnowflakeError>(id)
+            })
+            .copied()
+            .inspect(|id| self.on_resolved.fire(*id))?;
+        self.active_id(id)
+    }
+
+    /// Worker id to issue snowflakes with for the claimed `id`, errors once re-verify stopped
+    fn active_id(&self, id: WorkerId) -> Result<WorkerId, SnowflakeError> {
+        match self.active_reverify().stopped() {
+            Some(reason) => Err(SnowflakeError::ReverifyStopped(reason.to_string())),
+            None => Ok(self.promoted_or(id)),
+        }
+    }
+
+    /// Worker id if it's resolved already, never claims
+    fn claimed_id(&self) -> Option<WorkerId> {
+        self.worker_id.get().map(|id| self.promoted_or(*id))
+    }
+
+    /// Re-verify state of the worker id in use, the standby one once promoted
+    fn active_reverify(&self) -> &SharedReverifyState {
+        match self.promoted.load(AtomicOrdering::Acquire) {
+            NOT_PROMOTED => &self.reverify,
+            _ => &self.standby_reverify,
+        }
+    }
+
+    /// Promoted standby worker id, `id` if there is none
+    fn promoted_or(&self, id: WorkerId) -> WorkerId {
+        match self.promoted.load(AtomicOrdering::Acquire) {
+            NOT_PROMOTED => id,
+            promoted => promoted as WorkerId,
+        }
+    }
+
+    /// Calls `hook` once the worker id is resolved, right away if it already is
+    ///
+    /// Replaces a hook still waiting, see [GeneratorBuilder::on_worker_id_resolved]
+    pub fn on_worker_id_resolved(&self, hook: impl FnOnce(WorkerId) + Send + 'static) {
+        self.on_resolved
+            .set(Box::new(hook), || self.worker_id.get().copied());
+    }
+
+    /// Claims a worker id and the standby worker id if configured
+    fn claim(&self, timeout: Option<Duration>) -> Result<WorkerId, SnowflakeError> {
+        let (standby, promoted) = (self.standby.clone(), self.promoted.clone());
+        let promote: OnRevoked = Arc::new(move |id| {
+            let spare = standby
+                .lock()
+                .expect("Couldn't lock standby worker id")
+                .take();
+            match spare {
+                Some(spare) => {
+                    log::warn!("Worker id {} revoked, promoting standby {}", id, spare);
+                    promoted.store(spare as u32, AtomicOrdering::Release);
+                    true
+                }
+                None => false,
+            }
+        });
+        // Locked until the spare is stored, so neither re-verify can miss it
+        let mut slot = self
+            .standby
+            .lock()
+            .expect("Couldn't lock standby worker id");
+        let id = self.claim_lease(timeout, &self.reverify, Some(promote))?;
+        if self.config.standby {
+            let standby = self.standby.clone();
+            // A revoked standby is dropped, once promoted it's re-verified like the claimed one
+            let drop_spare: OnRevoked = Arc::new(move |id| {
+                let mut spare = standby.lock().expect("Couldn't lock standby worker id");
+                let revoked = *spare == Some(id);
+                if revoked {
+                    *spare = None;
+                }
+                revoked
+            });
+            match self
+                .claim_lease(timeout, &self.standby_reverify, Some(drop_spare))
+                .and_then(|spare| self.check_spare(spare))
+            {
+                Ok(spare) => *slot = Some(spare),
+                Err(e) => log::warn!("Couldn't claim standby worker id: {}", e),
+            }
+        }
+        Ok(id)
+    }
+
+    /// Checks the spare worker id like the claimed one, taking its host lock if enabled
+    fn check_spare(&self, spare: WorkerId) -> Result<WorkerId, SnowflakeError> {
+        check_worker_id(spare, &self.config)?;
+        if host_lock_enabled() {
+            let _ = self.standby_lock.set(HostLock::acquire(spare)?);
+        }
+        Ok(spare)
+    }
+
+    /// Claims a worker id, re-claiming right away if the lease is already expired
+    fn claim_lease(
+        &self,
+        timeout: Option<Duration>,
+        state: &SharedReverifyState,
+        on_revoked: Option<OnRevoked>,
+    ) -> Result<WorkerId, SnowflakeError> {
+        let mut reclaims = 0;
+        loop {
+            match coordinator::claim(
+                &self.config,
+                &self.coordinator,
+                state,
+                timeout,
+                on_revoked.clone(),
+            ) {
+                Err(e @ SnowflakeError::LeaseAlreadyExpired { .. })
+                    if reclaims < EXPIRED_LEASE_RECLAIMS =>
+                {
+                    log::warn!("Re-claiming worker id: {}", e);
+                    reclaims += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Generates `n` snowflakes under a single lock
+    pub async fn generate_batch(&self, usage_id: UsageId, n: usize) -> Vec<Snowflake> {
+        match self.try_generate_batch(usage_id, n).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+        }
+    }
+
+    /// Generates `n` snowflakes under a single lock
+    pub async fn try_generate_batch(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Result<Vec<Snowflake>, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        self.next_batch(worker_id, usage_id, n)
+    }
+
+    /// Generates `n` snowflakes under a single lock, erroring with the count generated so far
+    ///
+    /// Never panics and fails like [Generator::try_generate_sync] on a backwards clock or a tick
+    /// not coming in time, instead of continuing on the last timestamp. The partial batch is
+    /// discarded, [SnowflakeError::BatchFailed] reports its size
+    pub async fn generate_fallible_batch(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Result<Vec<Snowflake>, SnowflakeError> {
+        let failed = |generated, e| SnowflakeError::BatchFailed {
+            generated,
+            source: Box::new(e),
+        };
+        let worker_id = self.worker_id().map_err(|e| failed(0, e))?;
+        let _in_flight = self.admit(n).map_err(|e| failed(0, e))?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        let mut snowflakes = Vec::with_capacity(n);
+        while snowflakes.len() < n {
+            state
+                .advance_sync(&*self.clock)
+                .map_err(|e| failed(snowflakes.len(), e))?;
+            snowflakes.push(self.issue(&state, worker_id, usage_id));
+        }
+        self.generated(n, state.last_clock);
+        Ok(snowflakes)
+    }
+
+    /// Generates `n` snowflakes under a single lock, each strictly greater then the previous one
+    ///
+    /// Salting or [SnowflakeConfig::strict_monotonic] can break the order of a plain batch within
+    /// a tick, here sequence ids breaking it are skipped, advancing to the next tick as needed.
+    /// The safest batch for append-only logs, panics if generation fails
+    pub async fn generate_batch_monotone_across_time(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Vec<Snowflake> {
+        match self
+            .try_generate_batch_monotone_across_time(usage_id, n)
+            .await
+        {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_batch_monotone_across_time], but returns errors instead of
+    /// panicking
+    pub async fn try_generate_batch_monotone_across_time(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Result<Vec<Snowflake>, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        let _in_flight = self.admit(n)?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        let mut snowflakes: Vec<Snowflake> = Vec::with_capacity(n);
+        while snowflakes.len() < n {
+            state.advance(&*self.clock)?;
+            let snowflake = self.issue(&state, worker_id, usage_id);
+            if snowflakes.last().is_none_or(|prev| snowflake > *prev) {
+                snowflakes.push(snowflake);
+            }
+        }
+        debug_assert!(snowflakes.windows(2).all(|w| w[0] < w[1]));
+        self.generated(n, state.last_clock);
+        Ok(snowflakes)
+    }
+
+    /// Generates up to `n` snowflakes from the remaining sequence of the current tick
+    ///
+    /// Never waits for the clock or [SnowflakeConfig::rate_limit], callers loop as they allow more.
+    /// Panics if the worker id claim fails or the generator is draining
+    /// # Returns
+    /// * Vec - generated snowflakes
+    /// * usize - how many were generated, at most `n`
+    pub async fn generate_n_nonblocking(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> (Vec<Snowflake>, usize) {
+        match self.try_generate_n_nonblocking(usage_id, n).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+        }
+    }
+
+    /// Like [Generator::generate_n_nonblocking], but returns errors instead of panicking
+    pub async fn try_generate_n_nonblocking(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Result<(Vec<Snowflake>, usize), SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        // Not admitted, as waiting for the rate limit would block, see Generator::try_throttle
+        let _in_flight = self.drain.enter()?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        let mut snowflakes = Vec::with_capacity(n);
+        while snowflakes.len() < n && self.try_throttle() {
+            if !state.try_advance(&*self.clock) {
+                // The tick is exhausted, the token stays for the next call
+                self.give_back_token();
+                break;
+            }
+            snowflakes.push(self.issue(&state, worker_id, usage_id));
+        }
+        self.generated(snowflakes.len(), state.last_clock);
+        let count = snowflakes.len();
+        Ok((snowflakes, count))
+    }
+
+    /// Generates a new snowflake, passing it to `observer` first
+    ///
+    /// For logging or sampling ids without global metrics
+    pub async fn generate_with_observer(
+        &self,
+        usage_id: UsageId,
+        mut observer: impl FnMut(&Snowflake),
+    ) -> Snowflake {
+        let snowflake = self.generate(usage_id).await;
+        observer(&snowflake);
+        snowflake
+    }
+
+    /// Generates `n` snowflakes under a single lock, passing each of them to `observer`
+    ///
+    /// `observer` is called after the lock is released
+    pub async fn generate_batch_with_observer(
+        &self,
+        usage_id: UsageId,
+        n: usize,
+        observer: impl FnMut(&Snowflake),
+    ) -> Vec<Snowflake> {
+        let snowflakes = self.generate_batch(usage_id, n).await;
+        snowflakes.iter().for_each(observer);
+        snowflakes
+    }
+
+    /// Generates as many snowflakes as possible until the wall clock reaches `deadline`
+    pub async fn generate_until(&self, usage_id: UsageId, deadline: SystemTime) -> Vec<Snowflake> {
+        match self.try_generate_until(usage_id, deadline).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+        }
+    }
+
+    /// Generates as many snowflakes as possible until the wall clock reaches `deadline`
+    pub async fn try_generate_until(
+        &self,
+        usage_id: UsageId,
+        deadline: SystemTime,
+    ) -> Result<Vec<Snowflake>, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        let deadline = deadline
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        let mut snowflakes = Vec::new();
+        loop {
+            let _in_flight = self.admit(CHUNK_SIZE)?;
+            // Locked per chunk, so other callers aren't starved while warming up
+            let mut state = self.state.lock().expect("Couldn't lock generator state");
+            for _ in 0..CHUNK_SIZE {
+                state.advance(&*self.clock)?;
+                if state.prev_ts >= deadline {
+                    self.generated(snowflakes.len(), state.last_clock);
+                    return Ok(snowflakes);
+                }
+                snowflakes.push(self.issue(&state, worker_id, usage_id));
+            }
+        }
+    }
+
+    /// Reserves a snowflake whose timestamp is taken once it's finalized, panics if it fails
+    pub async fn reserve(&self, usage_id: UsageId) -> DeferredSnowflake<'_> {
+        match self.try_reserve(usage_id).await {
+            Ok(v) => v,
+            Err(e) => panic!("Couldn't reserve snowflake: {}", e),
+        }
+    }
+
+    /// Reserves a snowflake whose timestamp is taken once it's finalized
+    ///
+    /// Claims the worker id right away, so finalizing doesn't depend on the coordinator
+    pub async fn try_reserve(
+        &self,
+        usage_id: UsageId,
+    ) -> Result<DeferredSnowflake<'_>, SnowflakeError> {
+        let worker_id = self.worker_id()?;
+        Ok(DeferredSnowflake {
+            generator: self,
+            worker_id,
+            usage_id,
+        })
+    }
+
+    /// Returns an iterator lazily generating `n` snowflakes, one lock per chunk
+    pub fn batch_iter(&self, usage_id: UsageId, n: usize) -> BatchIter<'_> {
+        BatchIter {
+            generator: self,
+            usage_id,
+            remaining: n,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Returns an endless stream, generating `batch_size` snowflakes whenever its buffer drains
+    pub fn generate_batch_stream(&self, usage_id: UsageId, batch_size: usize) -> BatchStream<'_> {
+        BatchStream {
+            generator: self,
+            usage_id,
+            batch_size: batch_size.max(1),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Admits a generation of `n` snowflakes, counted in progress until the guard is dropped
+    ///
+    /// Errors once draining, waits for the rate limit otherwise
+    fn admit(&self, n: usize) -> Result<InFlight<'_>, SnowflakeError> {
+        let in_flight = self.drain.enter()?;
+        self.throttle(n);
+        Ok(in_flight)
+    }
+
+    /// Waits until the rate limit allows `n` more snowflakes
+    fn throttle(&self, n: usize) {
+        if let Some(limiter) = &self.limiter {
+            let mut limiter = limiter.lock().expect("Couldn't lock rate limiter");
+            for _ in 0..n {
+                while !limiter.try_take(self.clock.now_nanos()) {
+                    pause(Duration::from_micros(100));
+                }
+            }
+        }
+    }
+
+    /// Takes a single token of [SnowflakeConfig::rate_limit] without waiting
+    /// # Returns
+    /// * bool - if a snowflake may be generated now
+    fn try_throttle(&self) -> bool {
+        match &self.limiter {
+            Some(limiter) => limiter
+                .lock()
+                .expect("Couldn't lock rate limiter")
+                .try_take(self.clock.now_nanos()),
+            None => true,
+        }
+    }
+
+    /// Returns a token of [Generator::try_throttle] which didn't generate a snowflake
+    fn give_back_token(&self) {
+        if let Some(limiter) = &self.limiter {
+            limiter
+                .lock()
+                .expect("Couldn't lock rate limiter")
+                .give_back();
+        }
+    }
+
+    /// Counts `n` generated snowflakes, `now` is the last clock reading
+    #[inline]
+    fn generated(&self, n: usize, now: NanoTimestamp) {
+        otel::generated(n);
+        self.rate.record(n, now);
+    }
+
+    /// Salts the current snowflake of `state`, applying [SnowflakeConfig::strict_monotonic]
+    fn issue(&self, state: &State, worker_id: WorkerId, usage_id: UsageId) -> Snowflake {
+        let mut snowflake = state.snowflake(worker_id, usage_id).salted(self.salt);
+        if self.config.strict_monotonic {
+            let mut last = LAST_ISSUED
+                .lock()
+                .expect("Couldn't lock last issued snowflake");
+            if let Some(last) = *last {
+                if snowflake <= last {
+                    snowflake.timestamp =
+                        state.layout.truncate(last.timestamp) + state.layout.resolution_nanos();
+                }
+            }
+            *last = Some(snowflake);
+        }
+        snowflake
+    }
+
+    /// Builds the next snowflake
+    fn next(&self, worker_id: WorkerId, usage_id: UsageId) -> Result<Snowflake, SnowflakeError> {
+        self.next_with(worker_id, usage_id, &self.state, |state| {
+            state.advance(&*self.clock)
+        })
+        .map(|(snowflake, _)| snowflake)
+    }
+
+    /// Builds the next snowflake of `stripe`, moved to its next timestamp/sequence pair by
+    /// `advance`
+    /// # Returns
+    /// * Snowflake - the issued snowflake
+    /// * T - what `advance` returned
+    fn next_with<T>(
+        &self,
+        worker_id: WorkerId,
+        usage_id: UsageId,
+        stripe: &Mutex<State>,
+        advance: impl FnOnce(&mut State) -> Result<T, SnowflakeError>,
+    ) -> Result<(Snowflake, T), SnowflakeError> {
+        let _in_flight = self.admit(1)?;
+        let mut state = stripe.lock().expect("Couldn't lock generator state");
+        let advanced = advance(&mut state)?;
+        self.generated(1, state.last_clock);
+        Ok((self.issue(&state, worker_id, usage_id), advanced))
+    }
+
+    /// Builds the next `n` snowflakes without releasing the lock in between
+    pub(crate) fn next_batch(
+        &self,
+        worker_id: WorkerId,
+        usage_id: UsageId,
+        n: usize,
+    ) -> Result<Vec<Snowflake>, SnowflakeError> {
+        let _in_flight = self.admit(n)?;
+        let mut state = self.state.lock().expect("Couldn't lock generator state");
+        let snowflakes = (0..n)
+            .map(|_| {
+                state.advance(&*self.clock)?;
+                Ok(self.issue(&state, worker_id, usage_id))
+            })
+            .collect::<Result<Vec<_>, SnowflakeError>>()?;
+        self.generated(snowflakes.len(), state.last_clock);
+        Ok(snowflakes)
+    }
+}
+
+impl State {
+    /// Moves to the next free timestamp/sequence pair
+    /// # Returns
+    /// * bool - if it had to wait for the clock
+    fn advance(&mut self, clock: &dyn Clock) -> Result<bool, SnowflakeError> {
+        let mut waited = false;
+        loop {
+            if self.try_advance(clock) {
+                return Ok(waited);
+            }
+            match self.same_instant {
+                SameInstant::Wait => {
+                    waited = true;
+                    pause(Duration::from_nanos(10));
+                }
+                SameInstant::Borrow => {
+                    self.prev_ts += self.layout.resolution_nanos();
+                    self.sequence_id = *self.sequence_ids.start();
+                    self.observe_rate(self.last_clock);
+                    return Ok(waited);
+                }
+                SameInstant::Error => return Err(SnowflakeError::SequenceExhausted),
+            }
+        }
+    }
+
+    /// Time until `min_interval` passed since the current timestamp, zero once it did
+    fn wait_before(&self, clock: &dyn Clock, min_interval: Duration) -> Duration {
+        let earliest = self.prev_ts + min_interval.as_nanos();
+        let now = self.layout.truncate(clock.now_nanos());
+        Duration::from_nanos(earliest.saturating_sub(now).min(u64::MAX as u128) as u64)
+    }
+
+    /// Like [State::advance], but with at most `max_seq` sequence ids per timestamp
+    fn advance_bounded(
+        &mut self,
+        clock: &dyn Clock,
+        max_seq: SequenceId,
+    ) -> Result<bool, SnowflakeError> {
+        let full = self.sequence_ids.clone();
+        let end = full
+            .start()
+            .saturating_add(max_seq.saturating_sub(1))
+            .min(*full.end());
+        self.sequence_ids = *full.start()..=end;
+        let advanced = self.advance(clock);
+        self.sequence_ids = full;
+        advanced
+    }
+
+    /// Like [State::advance], but tries `hint` as clock reading first if it didn't go backwards
+    fn advance_with_hint(
+        &mut self,
+        clock: &dyn Clock,
+        hint: NanoTimestamp,
+    ) -> Result<bool, SnowflakeError> {
+        if hint >= self.last_clock && self.try_advance_at(hint) {
+            return Ok(false);
+        }
+        self.advance(clock)
+    }
+
+    /// Like [State::advance], but errors if the clock went backwards or waiting takes too long
+    fn advance_sync(&mut self, clock: &dyn Clock) -> Result<(), SnowflakeError> {
+        let mut deadline = None;
+        loop {
+            let last_clock = self.last_clock;
+            let regressions = self.regressions;
+            let advanced = self.try_advance(clock);
+            if self.regressions > regressions {
+                return Err(SnowflakeError::ClockRegressed {
+                    by: last_clock - self.last_clock,
+                });
+            }
+            if advanced {
+                return Ok(());
+            }
+            match self.same_instant {
+                SameInstant::Wait => {
+                    let deadline = *deadline.get_or_insert(self.last_clock + SYNC_TIMEOUT);
+                    if self.last_clock >= deadline {
+                        return Err(SnowflakeError::SequenceExhausted);
+                    }
+                    pause(Duration::from_nanos(10));
+                }
+                SameInstant::Borrow => {
+                    self.prev_ts += self.layout.resolution_nanos();
+                    self.sequence_id = *self.sequence_ids.start();
+                    self.observe_rate(self.last_clock);
+                    return Ok(());
+                }
+                SameInstant::Error => return Err(SnowflakeError::SequenceExhausted),
+            }
+        }
+    }
+
+    /// Moves to the next free timestamp/sequence pair if the current tick has one left
+    /// # Returns
+    /// * bool - if it moved, false if the sequence of the current tick is exhausted
+    fn try_advance(&mut self, clock: &dyn Clock) -> bool {
+        self.try_advance_at(clock.now_nanos())
+    }
+
+    /// Like [State::try_advance] with `now` as clock reading
+    fn try_advance_at(&mut self, now: NanoTimestamp) -> bool {
+        if now < self.last_clock {
+            self.clock_regressed(now);
+        }
+        self.last_clock = now;
+        let current_time = self.layout.truncate(now);
+        if current_time > self.prev_ts {
+            self.prev_ts = current_time;
+            self.sequence_id = *self.sequence_ids.start();
+            self.observe_rate(now);
+            return true;
+        }
+        // Same tick (or the clock went backwards), continue on the previous timestamp
+        if self.sequence_id < *self.sequence_ids.end() {
+            self.sequence_id += 1;
+            self.observe_rate(now);
+            return true;
+        }
+        false
+    }
+
+    /// Counts a generated snowflake, warning once a rate window exceeds the capacity threshold
+    fn observe_rate(&mut self, now: NanoTimestamp) {
+        let Some(percent) = self.capacity_warning else {
+            return;
+        };
+        let start = *self.window_start.get_or_insert(now);
+        self.window_ids += 1;
+        let elapsed = now.saturating_sub(start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let rate = self.window_ids * 1_000_000_000 / elapsed;
+        let capacity = self.layout.max_ids_per_second();
+        if rate * 100 >= capacity * percent as u128 {
+            self.capacity_warnings += 1;
+            if self
+                .last_capacity_log
+                .is_none_or(|at| now.abs_diff(at) >= CAPACITY_LOG_INTERVAL)
+            {
+                log::warn!(
+                    "Generating {} ids/s, over {}% of the {} ids/s a worker can generate, add workers",
+                    rate,
+                    percent,
+                    capacity
+                );
+                self.last_capacity_log = Some(now);
+            }
+        }
+        self.window_start = Some(now);
+        self.window_ids = 0;
+    }
+
+    /// Counts a backwards clock, warning at most every [REGRESSION_LOG_INTERVAL]
+    ///
+    /// Rate limited by the generator clock, as [std::time::Instant] isn't available everywhere
+    fn clock_regressed(&mut self, now: NanoTimestamp) {
+        self.regressions += 1;
+        if self
+            .last_regression_log
+            .is_none_or(|at| now.abs_diff(at) >= REGRESSION_LOG_INTERVAL)
+        {
+            log::warn!(
+                "Clock went backwards by {} ns, {} times so far",
+                self.last_clock - now,
+                self.regressions
+            );
+            self.last_regression_log = Some(now);
+        }
+    }
+
+    fn snowflake(&self, worker_id: WorkerId, usage_id: UsageId) -> Snowflake {
+        Snowflake {
+            timestamp: self.prev_ts,
+            worker_id,
+            sequence_id: self.sequence_id,
+            usage_id,
+        }
+    }
+}
+
+/// Generators with different worker ids sharing one timestamp and sequence
+///
+/// Snowflakes of all members are strictly increasing by `(timestamp, sequence_id)` in generation
+/// order, so they have a total order across generators independent of the worker id
+#[derive(Debug)]
+pub struct GeneratorGroup {
+    state: Arc<Mutex<State>>,
+}
+
+impl GeneratorGroup {
+    /// Creates an empty group, members have to use `layout`
+    pub fn new(layout: Layout) -> Self {
+        GeneratorGroup {
+            state: Arc::new(Mutex::new(State {
+                prev_ts: 0,
+                sequence_id: 0,
+                layout,
+                sequence_ids: 0..=layout.max_sequence_id(),
+                same_instant: SameInstant::default(),
+                last_clock: 0,
+                regressions: 0,
+                last_regression_log: None,
+                capacity_warning: None,
+                window_start: None,
+                window_ids: 0,
+                capacity_warnings: 0,
+                last_capacity_log: None,
+            })),
+        }
+    }
+
+    /// Sets what happens once the shared sequence is exhausted, defaults to [SameInstant::Wait]
+    pub fn same_instant(self, same_instant: SameInstant) -> Self {
+        self.state
+            .lock()
+            .expect("Couldn't lock generator state")
+            .same_instant = same_instant;
+        self
+    }
+
+    /// Builds a member of the group, errors if its layout differs from the group layout
+    ///
+    /// The same-instant strategy of `builder` is replaced by the one of the group
+    pub fn add(&self, builder: GeneratorBuilder) -> Result<Generator, SnowflakeError> {
+        let generator = builder.build()?;
+        let layout = self
+            .state
+            .lock()
+            .expect("Couldn't lock generator state")
+            .layout;
+        if *generator.layout() != layout {
+            return Err(SnowflakeError::InvalidLayout(
+                "layout differs from the generator group layout".to_string(),
+            ));
+        }
+        // The shared state covers the whole sequence range, stripes would collide with it
+        Ok(Generator {
+            state: self.state.clone(),
+            stripes: Vec::new(),
+            ..generator
+        })
+    }
+}
+
+/// Errors if `worker_id` isn't accepted by `config` or doesn't fit into its layout
+fn check_worker_id(worker_id: WorkerId, config: &SnowflakeConfig) -> Result<(), SnowflakeError> {
+    let min = *config.worker_id_range.start();
+    let max = (*config.worker_id_range.end()).min(config.layout.max_worker_id());
+    if worker_id < min || worker_id > max {
+        return Err(SnowflakeError::WorkerIdOutOfRange {
+            worker_id,
+            min,
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Static generators using their shard as worker id, created on first use
+#[derive(Debug)]
+pub(crate) struct Shards {
+    clock: Arc<dyn Clock>,
+    generators: Mutex<HashMap<WorkerId, Arc<Generator>>>,
+}
+
+impl Shards {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Shards {
+            clock,
+            generators: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the generator of `shard`, errors if it doesn't fit the [Layout::active] worker id
+    pub(crate) fn get(&self, shard: WorkerId) -> Result<Arc<Generator>, SnowflakeError> {
+        let mut generators = self.generators.lock().expect("Couldn't lock shards");
+        if let Some(generator) = generators.get(&shard) {
+            return Ok(generator.clone());
+        }
+        let generator = Arc::new(
+            Generator::builder()
+                .worker_id_source(WorkerIdSource::Static(shard))
+                .layout(Layout::active())
+                .clock(self.clock.clone())
+                .build()?,
+        );
+        generators.insert(shard, generator.clone());
+        Ok(generator)
+    }
+}
+
+/// Snowflake of [Generator::generate_with_deadline_and_fallback] or [Snowflake::new_best_effort]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FallbackSnowflake {
+    /// Generated snowflake
+    pub snowflake: Snowflake,
+    /// If it uses the fallback worker id, as no worker id was claimed in time
+    pub provisional: bool,
+}
+
+/// Generations in progress, see [Generator::begin_drain]
+#[derive(Debug, Default)]
+struct Drain {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    /// Tasks of [Generator::await_drained] waiting for the last generation
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl Drain {
+    /// Counts a generation in progress, errors once draining
+    fn enter(&self) -> Result<InFlight<'_>, SnowflakeError> {
+        self.in_flight.fetch_add(1, AtomicOrdering::SeqCst);
+        let in_flight = InFlight(self);
+        if self.draining.load(AtomicOrdering::SeqCst) {
+            return Err(SnowflakeError::Draining);
+        }
+        Ok(in_flight)
+    }
+
+    fn poll_drained(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.in_flight.load(AtomicOrdering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        self.waiters
+            .lock()
+            .expect("Couldn't lock drain waiters")
+            .push(cx.waker().clone());
+        // The last generation may have finished before the waker was registered
+        if self.in_flight.load(AtomicOrdering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// Generation in progress, admitted by [Drain::enter]
+struct InFlight<'a>(&'a Drain);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, AtomicOrdering::SeqCst) == 1 {
+            let waiters =
+                std::mem::take(&mut *self.0.waiters.lock().expect("Couldn't lock drain waiters"));
+            waiters.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+/// How a snowflake of [Generator::generate_with_metadata] was generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationMetadata {
+    /// If it had to wait for the clock, as the sequence of the tick was exhausted
+    pub waited: bool,
+    /// Sequence id taken, before salting
+    pub sequence_id: SequenceId,
+    /// If it's on a later timestamp then the previous snowflake of the generator
+    pub timestamp_advanced: bool,
+}
+
+/// How close a snowflake of [Generator::generate_with_deadline_metadata] came to its deadline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineMetadata {
+    /// Time generation took, including the claim
+    pub elapsed: Duration,
+    /// Time left until the deadline, zero if it was exceeded
+    pub remaining: Duration,
+    /// Time past the deadline, zero if it was met
+    pub exceeded: Duration,
+}
+
+/// Snowflake with a claimed worker id, stamped with the time it's finalized
+///
+/// Created by [Generator::reserve]. Finalizing takes the sequence from the generator like any
+/// other snowflake, so deferred snowflakes stay unique no matter in which order they're finalized
+#[derive(Debug)]
+pub struct DeferredSnowflake<'a> {
+    generator: &'a Generator,
+    worker_id: WorkerId,
+    usage_id: UsageId,
+}
+
+impl DeferredSnowflake<'_> {
+    /// Worker id the snowflake will have
+    pub fn worker_id(&self) -> WorkerId {
+        self.worker_id
+    }
+
+    /// Stamps the snowflake with the current time
+    pub fn finalize(self) -> Result<Snowflake, SnowflakeError> {
+        self.generator.next(self.worker_id, self.usage_id)
+    }
+}
+
+/// Endless stream of snowflakes, generated in batches
+///
+/// Created by [Generator::generate_batch_stream]
+#[derive(Debug)]
+pub struct BatchStream<'a> {
+    generator: &'a Generator,
+    usage_id: UsageId,
+    batch_size: usize,
+    buffer: VecDeque<Snowflake>,
+}
+
+impl Stream for BatchStream<'_> {
+    type Item = Snowflake;
+
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.buffer.is_empty() {
+            let batch = this.generator.worker_id().and_then(|worker_id| {
+                this.generator
+                    .next_batch(worker_id, this.usage_id, this.batch_size)
+            });
+            this.buffer = match batch {
+                Ok(v) => v.into(),
+                Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+            };
+        }
+        Poll::Ready(this.buffer.pop_front())
+    }
+}
+
+/// Blocks the current thread for `duration`
+#[cfg(not(target_arch = "wasm32"))]
+fn pause(duration: Duration) {
+    thread::sleep(duration)
+}
+
+/// Threads can't sleep on wasm32, so only hints the cpu instead
+#[cfg(target_arch = "wasm32")]
+fn pause(_duration: Duration) {
+    std::hint::spin_loop()
+}
+
+/// Iterator generating a fixed number of snowflakes in chunks, without collecting them
+///
+/// Created by [Generator::batch_iter], panics if generation fails
+#[derive(Debug)]
+pub struct BatchIter<'a> {
+    generator: &'a Generator,
+    usage_id: UsageId,
+    remaining: usize,
+    buffer: VecDeque<Snowflake>,
+}
+
+impl Iterator for BatchIter<'_> {
+    type Item = Snowflake;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && self.remaining > 0 {
+            let n = self.remaining.min(CHUNK_SIZE);
+            let batch = self
+                .generator
+                .worker_id()
+                .and_then(|worker_id| self.generator.next_batch(worker_id, self.usage_id, n));
+            self.buffer = match batch {
+                Ok(v) => v.into(),
+                Err(e) => panic!("Couldn't generate snowflakes: {}", e),
+            };
+            self.remaining -= n;
+        }
+        self.buffer.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.remaining + self.buffer.len();
+        (len, Some(len))
+    }
+}
+
+impl ExactSizeIterator for BatchIter<'_> {}
+
+/// Formats tags of [Generator::generate_and_tag] as space separated `key=value` pairs
+#[cfg(feature = "tracing")]
+struct Tags<'a>(&'a [(&'a str, &'a str)]);
+
+#[cfg(feature = "tracing")]
+impl std::fmt::Display for Tags<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns if ```SNOWFLAKE.HOST_LOCK``` enables the host-local worker id check
+fn host_lock_enabled() -> bool {
+    matches!(
+        env::var("SNOWFLAKE.HOST_LOCK").as_deref(),
+        Ok("1") | Ok("true")
+    )
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::generator::{
+        with_usage_scope, GenerationMetadata, Generator, GeneratorGroup, SameInstant, Shards,
+        WorkerIdSource,
+    };
+    use crate::layout::{ByteOrder, Layout};
+    use crate::retry::RetryPolicy;
+    use crate::{
+        mock, IdempotencyCache, ParseError, RateLimit, SequenceId, Snowflake, SnowflakeConfig,
+        SnowflakeError, PRE_TIME,
+    };
+    use futures::StreamExt;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// Serves leases that have to be re-verified after one second
+    fn short_lease(reverify_status: u16) -> mock::MockCoordinator {
+        mock::serve(move |path| {
+            let body = format!(
+                r#"{{"id":5,"ts":{},"re_ts":{}}}"#,
+                mock::now_secs(),
+                mock::now_secs() + PRE_TIME + 1
+            );
+            if path.starts_with("/reverify/") {
+                (reverify_status, body)
+            } else {
+                (200, body)
+            }
+        })
+    }
+
+    #[tokio::test]
+    pub async fn test_retry_until_coordinator_up() {
+        let attempts = AtomicUsize::new(0);
+        let coordinator = mock::serve(move |_| {
+            if attempts.fetch_add(1, Ordering::SeqCst) < 3 {
+                (503, String::new())
+            } else {
+                (200, mock::lease(7))
+            }
+        });
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(10),
+            max_total_duration: None,
+        };
+
+        let generator = Generator::new(&coordinator.url);
+        let snowflake = generator.generate_with_retry(3, &policy).await.unwrap();
+        assert_eq!(snowflake.worker_id, 7);
+        assert_eq!(snowflake.usage_id, 3);
+        assert_eq!(coordinator.hits(), 4);
+    }
+
+    #[tokio::test]
+    pub async fn test_retry_gives_up() {
+        let coordinator = mock::serve(|_| (503, String::new()));
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_total_duration: None,
+        };
+
+        let generator = Generator::new(&coordinator.url);
+        assert!(generator.generate_with_retry(0, &policy).await.is_err());
+        assert_eq!(coordinator.hits(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_retry_permanent() {
+        let coordinator = mock::serve(|_| (200, mock::lease(2000)));
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_total_duration: None,
+        };
+
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .worker_id_range(0..=100)
+            .build();
+        let generator = Generator::with_config(config);
+        assert!(matches!(
+            generator.generate_with_retry(0, &policy).await,
+            Err(SnowflakeError::WorkerIdOutOfRange { .. })
+        ));
+        assert_eq!(coordinator.hits(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_retry_time_budget() {
+        let coordinator = mock::serve(|_| (503, String::new()));
+        let policy = RetryPolicy {
+            max_attempts: 1000,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(20),
+            max_total_duration: Some(Duration::from_millis(200)),
+        };
+
+        let generator = Generator::new(&coordinator.url);
+        let start = Instant::now();
+        assert!(generator.generate_with_retry(0, &policy).await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert!(
+            (2..=11).contains(&coordinator.hits()),
+            "{}",
+            coordinator.hits()
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_reverify_state_success() {
+        let coordinator = short_lease(200);
+        let generator = Generator::new(&coordinator.url);
+        assert_eq!(generator.reverify_state().next_attempt, None);
+        generator.generate(0).await;
+        assert!(generator.reverify_state().next_attempt.is_some());
+        assert_eq!(generator.reverify_state().last_success, None);
+
+        sleep(Duration::from_millis(2500));
+        let state = generator.reverify_state();
+        assert!(state.last_success.is_some());
+        assert_eq!(state.consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_reverify_state_failure() {
+        let coordinator = short_lease(503);
+        let generator = Generator::new(&coordinator.url);
+        generator.generate(0).await;
+
+        sleep(Duration::from_millis(2500));
+        let state = generator.reverify_state();
+        assert_eq!(state.last_success, None);
+        assert!(state.consecutive_failures >= 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_batch_stream() {
+        let coordinator = mock::serve(|_| (200, mock::lease(3)));
+        let generator = Generator::new(&coordinator.url);
+        let snowflakes: Vec<_> = generator
+            .generate_batch_stream(1, 64)
+            .take(1000)
+            .collect()
+            .await;
+
+        assert_eq!(snowflakes.len(), 1000);
+        assert!(snowflakes
+            .windows(2)
+            .all(|w| w[0].to_u128() < w[1].to_u128()));
+        assert_eq!(coordinator.hits(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_until() {
+        let coordinator = mock::serve(|_| (200, mock::lease(3)));
+        let generator = Generator::new(&coordinator.url);
+        let deadline = SystemTime::now() + Duration::from_millis(20);
+        let mut snowflakes = generator.generate_until(0, deadline).await;
+
+        assert!(!snowflakes.is_empty());
+        let deadline = deadline.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        assert!(snowflakes.iter().all(|s| s.timestamp < deadline));
+        let count = snowflakes.len();
+        snowflakes.sort_unstable_by_key(|s| s.to_u128());
+        snowflakes.dedup_by_key(|s| s.to_u128());
+        assert_eq!(snowflakes.len(), count);
+    }
+
+    #[tokio::test]
+    pub async fn test_backpressure() {
+        let coordinator = mock::serve(|_| (200, mock::lease(3)));
+        let generator =
+            Generator::new(&coordinator.url).with_clock(Arc::new(mock::StepClock::new(1_000, 300)));
+        let (tx, rx) = channel();
+
+        for _ in 0..=SequenceId::MAX as usize {
+            generator.generate_with_backpressure(0, &tx).await.unwrap();
+        }
+        assert!(rx.try_recv().is_err());
+
+        let snowflake = generator.generate_with_backpressure(0, &tx).await.unwrap();
+        assert!(rx.try_recv().is_ok());
+        assert_eq!(snowflake.timestamp, 1_001);
+        assert_eq!(snowflake.sequence_id, 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_salted() {
+        let coordinator = mock::serve(|_| (200, mock::lease(3)));
+        let salt = Snowflake::process_salt();
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .salt(Some(salt))
+            .build();
+        let generator =
+            Generator::with_config(config).with_clock(Arc::new(mock::StepClock::new(1_000, 100)));
+        let snowflakes = generator.generate_batch(9, 1000).await;
+
+        let mut unique = snowflakes.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), snowflakes.len());
+
+        for (i, snowflake) in snowflakes.iter().enumerate() {
+            let decoded = snowflake.unsalted(salt);
+            assert_eq!(decoded.usage_id, 9);
+            assert_eq!(decoded.sequence_id as usize, i % 100);
+            assert_eq!(decoded.worker_id, 3);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_builder() {
+        let layout = Layout {
+            timestamp_bits: 41,
+            worker_bits: 10,
+            sequence_bits: 2,
+            usage_bits: 8,
+            resolution: Duration::from_micros(1),
+            ..Layout::DEFAULT
+        };
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1000))
+            .clock(Arc::new(mock::StepClock::new(1_000_000_000, 1_000)))
+            .layout(layout)
+            .epoch(UNIX_EPOCH + Duration::from_secs(1))
+            .same_instant(SameInstant::Borrow)
+            .build()
+            .unwrap();
+
+        let snowflakes = generator.generate_batch(2, 10).await;
+        let sequences: Vec<_> = snowflakes.iter().map(|s| s.sequence_id).collect();
+        assert_eq!(sequences, [0, 1, 2, 3, 0, 1, 2, 3, 0, 1]);
+        assert_eq!(snowflakes[4].timestamp, 1_000_001_000);
+        for snowflake in &snowflakes {
+            assert_eq!(snowflake.worker_id, 1000);
+            assert_eq!(
+                generator
+                    .layout()
+                    .unpack(generator.layout().pack(snowflake)),
+                *snowflake
+            );
+        }
+
+        let err = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1024))
+            .layout(layout)
+            .build();
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_group_order() {
+        let clock = Arc::new(mock::StepClock::new(1_000, 50));
+        let group = GeneratorGroup::new(Layout::DEFAULT);
+        let first = group
+            .add(
+                Generator::builder()
+                    .worker_id_source(WorkerIdSource::Static(9))
+                    .clock(clock.clone()),
+            )
+            .unwrap();
+        let second = group
+            .add(
+                Generator::builder()
+                    .worker_id_source(WorkerIdSource::Static(2))
+                    .clock(clock),
+            )
+            .unwrap();
+
+        let mut snowflakes = Vec::new();
+        for i in 0..500 {
+            let generator = if i % 3 == 0 { &second } else { &first };
+            snowflakes.push(generator.generate(0).await);
+        }
+        assert!(snowflakes
+            .windows(2)
+            .all(|w| (w[0].timestamp, w[0].sequence_id) < (w[1].timestamp, w[1].sequence_id)));
+
+        let other_layout = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .layout(Layout {
+                sequence_bits: 4,
+                ..Layout::DEFAULT
+            });
+        assert!(group.add(other_layout).is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_clock_regressions() {
+        let clock = Arc::new(mock::ScriptedClock::new(vec![
+            1_000, 2_000, 1_500, 3_000, 500,
+        ]));
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock)
+            .build()
+            .unwrap();
+
+        let snowflakes = generator.generate_batch(0, 5).await;
+        assert_eq!(generator.clock_regressions(), 2);
+        assert!(snowflakes.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test]
+    pub async fn test_deferred() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(4))
+            .clock(Arc::new(mock::StepClock::new(1_000, 1)))
+            .build()
+            .unwrap();
+        let mut reserved = Vec::new();
+        for _ in 0..3 {
+            reserved.push(generator.reserve(6).await);
+        }
+        let before = generator.generate(6).await;
+
+        let last = reserved.pop().unwrap().finalize().unwrap();
+        let first = reserved.remove(0).finalize().unwrap();
+        let middle = reserved.pop().unwrap().finalize().unwrap();
+        let finalized = [last, first, middle];
+
+        assert!(finalized
+            .iter()
+            .all(|s| s.worker_id == 4 && s.usage_id == 6));
+        assert!(before.timestamp < last.timestamp);
+        assert!(finalized
+            .windows(2)
+            .all(|w| w[0].timestamp < w[1].timestamp));
+    }
+
+    #[tokio::test]
+    pub async fn test_rate_limit() {
+        let config = SnowflakeConfig::builder("")
+            .rate_limit(Some(RateLimit {
+                per_second: 200,
+                burst: 10,
+            }))
+            .build();
+        let generator = Generator::builder()
+            .config(config)
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+
+        let start = Instant::now();
+        generator.generate_batch(0, 10).await;
+        assert!(
+            start.elapsed() < Duration::from_millis(40),
+            "burst isn't limited"
+        );
+        for _ in 0..40 {
+            generator.generate(0).await;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(190), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    pub async fn test_generate_with_span() {
+        use std::sync::Mutex;
+        use tracing::span::Id;
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+        use tracing_subscriber::registry::LookupSpan;
+
+        /// Records the span of every event
+        struct EventParents(Arc<Mutex<Vec<Option<Id>>>>);
+
+        impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S>
+            for EventParents
+        {
+            fn on_event(&self, event: &tracing::Event<'_>, ctx: Context<'_, S>) {
+                let parent = ctx.event_span(event).map(|s| s.id());
+                self.0.lock().unwrap().push(parent);
+            }
+        }
+
+        let parents = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(EventParents(parents.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+
+        let request = tracing::info_span!("request");
+        generator.generate_with_span(0, &request).await;
+        assert_eq!(*parents.lock().unwrap(), [request.id()]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    pub async fn test_generate_and_tag() {
+        use std::fmt::Debug;
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing_subscriber::layer::{Context, SubscriberExt};
+
+        /// Records the fields of every event
+        struct EventFields(Arc<Mutex<Vec<(String, String)>>>);
+
+        impl Visit for EventFields {
+            fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+                let value = format!("{:?}", value);
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((field.name().to_string(), value));
+            }
+        }
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for EventFields {
+            fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+                event.record(&mut EventFields(self.0.clone()));
+            }
+        }
+
+        let fields = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(EventFields(fields.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+
+        let snowflake = generator
+            .generate_and_tag(4, &[("order", "42"), ("tenant", "acme")])
+            .await;
+        let fields = fields.lock().unwrap();
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+        };
+        assert_eq!(field("snowflake"), Some(snowflake.to_string()));
+        assert_eq!(field("tags").as_deref(), Some("order=42 tenant=acme"));
+    }
+
+    #[tokio::test]
+    pub async fn test_shards() {
+        let shards = Shards::new(Arc::new(mock::StepClock::new(1_000, 1_000)));
+        let mut snowflakes = Vec::new();
+        for shard in [3, 8, 3, 3, 8] {
+            snowflakes.push(shards.get(shard).unwrap().generate(0).await);
+        }
+        let fields: Vec<_> = snowflakes
+            .iter()
+            .map(|s| (s.worker_id, s.sequence_id))
+            .collect();
+        assert_eq!(fields, [(3, 0), (8, 0), (3, 1), (3, 2), (8, 1)]);
+        assert!(Arc::ptr_eq(
+            &shards.get(3).unwrap(),
+            &shards.get(3).unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_n_nonblocking() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(Arc::new(mock::StepClock::new(1_000, 10_000)))
+            .build()
+            .unwrap();
+        generator.generate_batch(0, 250).await;
+
+        let start = Instant::now();
+        let (snowflakes, count) = generator.generate_n_nonblocking(0, 20).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(count, 6);
+        assert_eq!(snowflakes.len(), count);
+        assert_eq!(snowflakes[5].sequence_id, SequenceId::MAX);
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_n_nonblocking_rate_limit() {
+        let clock = Arc::new(mock::ManualClock::default());
+        clock.set(1_000);
+        let config = SnowflakeConfig::builder("")
+            .rate_limit(Some(RateLimit {
+                per_second: 1,
+                burst: 3,
+            }))
+            .build();
+        let generator = Generator::builder()
+            .config(config)
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+
+        let start = Instant::now();
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 3);
+        clock.set(1_001);
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 0);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // An exhausted tick doesn't use up tokens
+        let generator = Generator::builder()
+            .config(
+                SnowflakeConfig::builder("")
+                    .rate_limit(Some(RateLimit {
+                        per_second: 1,
+                        burst: 4,
+                    }))
+                    .build(),
+            )
+            .layout(Layout {
+                sequence_bits: 1,
+                ..Layout::DEFAULT
+            })
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 2);
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 0);
+        clock.set(1_002);
+        assert_eq!(generator.generate_n_nonblocking(0, 20).await.1, 2);
+    }
+
+    #[test]
+    pub fn test_try_generate_sync() {
+        let unclaimed = Generator::new("http://127.0.0.1:1");
+        assert!(matches!(
+            unclaimed.try_generate_sync(0),
+            Err(SnowflakeError::WorkerIdUninitialized)
+        ));
+
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(2))
+            .clock(Arc::new(mock::ScriptedClock::new(vec![
+                1_000, 2_000, 1_500,
+            ])))
+            .build()
+            .unwrap();
+        assert_eq!(generator.try_generate_sync(0).unwrap().timestamp, 1_000);
+        assert_eq!(generator.try_generate_sync(0).unwrap().timestamp, 2_000);
+        assert!(matches!(
+            generator.try_generate_sync(0),
+            Err(SnowflakeError::ClockRegressed { by: 500 })
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_usage_scope() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let (outer, inner) = with_usage_scope(7, || {
+            let inner = with_usage_scope(9, || generator.generate_scoped());
+            (generator.generate_scoped(), inner)
+        });
+        assert_eq!(outer.await.usage_id, 7);
+        assert_eq!(inner.await.usage_id, 9);
+        assert_eq!(generator.generate_scoped().await.usage_id, 0);
+
+        let batch = with_usage_scope(5, || generator.generate_scoped_batch(300)).await;
+        assert_eq!(batch.len(), 300);
+        assert!(batch.iter().all(|s| s.usage_id == 5));
+    }
+
+    #[tokio::test]
+    pub async fn test_expired_lease() {
+        let claims = AtomicUsize::new(0);
+        let coordinator = mock::serve(move |_| {
+            if claims.fetch_add(1, Ordering::SeqCst) == 0 {
+                let now = mock::now_secs();
+                let body = format!(r#"{{"id":8,"ts":{},"re_ts":{}}}"#, now, now - 10);
+                (200, body)
+            } else {
+                (200, mock::lease(8))
+            }
+        });
+        let generator = Generator::new(&coordinator.url);
+        assert_eq!(generator.generate(0).await.worker_id, 8);
+        assert_eq!(coordinator.hits(), 2);
+
+        let expired = mock::serve(|_| {
+            let now = mock::now_secs();
+            (
+                200,
+                format!(r#"{{"id":8,"ts":{},"re_ts":{}}}"#, now, now - 10),
+            )
+        });
+        let generator = Generator::new(&expired.url);
+        assert!(matches!(
+            generator.try_generate(0).await,
+            Err(SnowflakeError::LeaseAlreadyExpired { .. })
+        ));
+        assert_eq!(expired.hits(), 4);
+    }
+
+    #[tokio::test]
+    pub async fn test_observer() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let mut seen = Vec::new();
+        let single = generator.generate_with_observer(2, |s| seen.push(*s)).await;
+        let batch = generator
+            .generate_batch_with_observer(2, 100, |s| seen.push(*s))
+            .await;
+
+        let mut generated = vec![single];
+        generated.extend(batch);
+        assert_eq!(seen, generated);
+    }
+
+    #[tokio::test]
+    pub async fn test_deadline_fallback() {
+        let coordinator = mock::serve(|_| {
+            sleep(Duration::from_millis(500));
+            (200, mock::lease(3))
+        });
+        let generator = Generator::new(&coordinator.url);
+        let start = Instant::now();
+        let result = generator
+            .generate_with_deadline_and_fallback(1, Duration::from_millis(100), 999)
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert!(result.provisional);
+        assert_eq!(result.snowflake.worker_id, 999);
+
+        let fast = mock::serve(|_| (200, mock::lease(3)));
+        let generator = Generator::new(&fast.url);
+        let result = generator
+            .generate_with_deadline_and_fallback(1, Duration::from_millis(100), 999)
+            .await
+            .unwrap();
+        assert!(!result.provisional);
+        assert_eq!(result.snowflake.worker_id, 3);
+    }
+
+    #[test]
+    pub fn test_batch_iter() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let iter = generator.batch_iter(4, 1000);
+        assert_eq!(iter.len(), 1000);
+        let snowflakes: HashSet<_> = iter.collect();
+        assert_eq!(snowflakes.len(), 1000);
+        assert!(snowflakes.iter().all(|s| s.usage_id == 4));
+        assert_eq!(generator.batch_iter(4, 0).count(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_with_ts_hint() {
+        let clock = Arc::new(mock::ScriptedClock::new(vec![10_000]));
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let mut prev = None;
+        for hint in 1_000..1_100 {
+            let snowflake = generator.generate_with_ts_hint(0, hint).await;
+            assert_eq!(snowflake.timestamp, hint);
+            assert!(prev < Some(snowflake));
+            prev = Some(snowflake);
+        }
+        assert_eq!(clock.reads(), 0);
+        // Stale hint falls back to the clock
+        let snowflake = generator.generate_with_ts_hint(0, 500).await;
+        assert_eq!(snowflake.timestamp, 10_000);
+        assert!(prev < Some(snowflake));
+        assert_eq!(clock.reads(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_self_test() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        generator.self_test(5000).await.unwrap();
+
+        // Clock jumping back like the sequence-reset bug did
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(Arc::new(mock::ScriptedClock::new(vec![
+                2_000, 2_001, 1_000, 1_001, 3_000,
+            ])))
+            .build()
+            .unwrap();
+        let e = generator.self_test(10).await.unwrap_err();
+        assert!(matches!(e, SnowflakeError::SelfTestFailed(_)), "{}", e);
+    }
+
+    #[tokio::test]
+    pub async fn test_strict_monotonic() {
+        let build = |worker_id, now| {
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").strict_monotonic(true).build())
+                .worker_id_source(WorkerIdSource::Static(worker_id))
+                .clock(Arc::new(mock::StepClock::new(now, 3)))
+                .build()
+                .unwrap()
+        };
+        // Second generator's clock is far behind
+        let generators = [build(2, 10_000), build(1, 5_000)];
+        let mut prev: Option<Snowflake> = None;
+        for i in 0..200u8 {
+            let generator = &generators[i as usize % 2];
+            let snowflake = generator.generate(i % 3).await;
+            assert!(prev < Some(snowflake), "{:?} after {:?}", snowflake, prev);
+            prev = Some(snowflake);
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_and_persist() {
+        #[derive(Default)]
+        struct Log {
+            written: Vec<u8>,
+            flushes: usize,
+            fail: bool,
+        }
+        impl std::io::Write for Log {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.fail {
+                    return Err(std::io::Error::other("disk full"));
+                }
+                self.written.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let mut log = Log::default();
+        let snowflake = generator.generate_and_persist(2, &mut log).await.unwrap();
+        assert_eq!(log.written, snowflake.to_bytes());
+        assert_eq!(log.flushes, 1);
+
+        let little_endian = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .layout(Layout {
+                byte_order: ByteOrder::LittleEndian,
+                ..Layout::DEFAULT
+            })
+            .build()
+            .unwrap();
+        let mut le_log = Log::default();
+        let snowflake = little_endian
+            .generate_and_persist(2, &mut le_log)
+            .await
+            .unwrap();
+        assert_eq!(le_log.written, snowflake.to_bytes_le());
+
+        log.fail = true;
+        let e = generator
+            .generate_and_persist(2, &mut log)
+            .await
+            .unwrap_err();
+        assert!(matches!(e, SnowflakeError::Io(_)));
+        assert_eq!(log.written.len(), 12);
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    #[should_panic(expected = "Duplicate snowflake")]
+    pub async fn test_generate_unique_in() {
+        let build = || {
+            Generator::builder()
+                .worker_id_source(WorkerIdSource::Static(1))
+                .clock(Arc::new(mock::ScriptedClock::new(vec![1_000])))
+                .build()
+                .unwrap()
+        };
+        let mut set = HashSet::new();
+        let generator = build();
+        for _ in 0..10 {
+            generator.generate_unique_in(0, &mut set).await;
+        }
+        assert_eq!(set.len(), 10);
+        // Second generator with the same worker id and clock repeats the first snowflake
+        build().generate_unique_in(0, &mut set).await;
+    }
+
+    #[tokio::test]
+    pub async fn test_provisioned_deferred_skew() {
+        let attempts = AtomicUsize::new(0);
+        let coordinator = mock::serve(move |path| {
+            assert_eq!(path, "/time");
+            if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                (503, String::new())
+            } else {
+                (200, format!(r#"{{"ts":{}}}"#, mock::now_secs() + 30))
+            }
+        });
+        let config = SnowflakeConfig::builder(&coordinator.url)
+            .retry(RetryPolicy {
+                max_attempts: 10,
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(10),
+                max_total_duration: None,
+            })
+            .build();
+        let generator = Generator::builder()
+            .config(config)
+            .worker_id_source(WorkerIdSource::Provisioned(5))
+            .build()
+            .unwrap();
+        assert_eq!(generator.generate(0).await.worker_id, 5);
+
+        let start = Instant::now();
+        while generator.deferred_skew().is_none() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "skew not measured"
+            );
+            sleep(Duration::from_millis(10));
+        }
+        assert!((29..=31).contains(&generator.deferred_skew().unwrap().diff));
+        assert_eq!(coordinator.hits(), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_remaining_sequence() {
+        let clock = Arc::new(mock::ManualClock::default());
+        clock.set(1_000);
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .layout(Layout {
+                sequence_bits: 2,
+                ..Layout::DEFAULT
+            })
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        assert_eq!(generator.remaining_sequence(), 4);
+        for remaining in (0..4).rev() {
+            generator.generate(0).await;
+            assert_eq!(generator.remaining_sequence(), remaining);
+        }
+        clock.set(1_001);
+        assert_eq!(generator.remaining_sequence(), 4);
+        assert_eq!(generator.generate(0).await.sequence_id, 0);
+        assert_eq!(generator.remaining_sequence(), 3);
+    }
+
+    #[test]
+    pub fn test_best_effort() {
+        let coordinator = mock::serve(|_| {
+            sleep(Duration::from_millis(300));
+            (200, mock::lease(7))
+        });
+        let generator = Arc::new(Generator::new(&coordinator.url));
+        let start = Instant::now();
+        let first = generator.generate_best_effort(1);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert!(first.provisional);
+        assert_eq!(first.snowflake.usage_id, 1);
+
+        loop {
+            let next = generator.generate_best_effort(1);
+            if !next.provisional {
+                assert_eq!(next.snowflake.worker_id, 7);
+                break;
+            }
+            assert_eq!(next.snowflake.worker_id, first.snowflake.worker_id);
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "claim didn't finish"
+            );
+            sleep(Duration::from_millis(20));
+        }
+        assert_eq!(coordinator.hits(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_throttled() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let interval = Duration::from_millis(2);
+        let mut prev = generator.generate_throttled(0, interval).await;
+        for _ in 0..5 {
+            let next = generator.generate_throttled(0, interval).await;
+            assert!(next.timestamp - prev.timestamp >= interval.as_nanos());
+            prev = next;
+        }
+
+        // Waiting doesn't hold the state lock
+        let interval = Duration::from_millis(300);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                sleep(Duration::from_millis(50));
+                let start = Instant::now();
+                generator.generate_sync(0);
+                assert!(start.elapsed() < Duration::from_millis(100));
+            });
+            let start = Instant::now();
+            futures::executor::block_on(generator.generate_throttled(0, interval));
+            assert!(start.elapsed() >= Duration::from_millis(200));
+        });
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_idempotent() {
+        let clock = Arc::new(mock::ManualClock::default());
+        clock.set(1_000_000);
+        let generator = Generator::builder()
+            .config(
+                SnowflakeConfig::builder("")
+                    .idempotency(IdempotencyCache {
+                        capacity: 2,
+                        ttl: Duration::from_secs(60),
+                    })
+                    .build(),
+            )
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let a = generator.generate_idempotent(0, "a").await;
+        assert_eq!(generator.generate_idempotent(0, "a").await, a);
+        let b = generator.generate_idempotent(0, "b").await;
+        assert_ne!(a, b);
+
+        // "b" is the least recently used key once "a" is read again
+        assert_eq!(generator.generate_idempotent(0, "a").await, a);
+        let c = generator.generate_idempotent(0, "c").await;
+        assert_ne!(generator.generate_idempotent(0, "b").await, b);
+
+        clock.set(1_000_000 + Duration::from_secs(59).as_nanos());
+        assert_eq!(generator.generate_idempotent(0, "c").await, c);
+        clock.set(1_000_000 + Duration::from_secs(60).as_nanos());
+        assert_ne!(generator.generate_idempotent(0, "c").await, c);
+
+        // Concurrent calls for a key agree on one snowflake
+        let minted: HashSet<_> = std::thread::scope(|s| {
+            let calls: Vec<_> = (0..8)
+                .map(|_| {
+                    s.spawn(|| futures::executor::block_on(generator.generate_idempotent(0, "d")))
+                })
+                .collect();
+            calls.into_iter().map(|c| c.join().unwrap()).collect()
+        });
+        assert_eq!(minted.len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_capacity_warning() {
+        // 16 snowflakes per tick of 100 ms, so 160 per second
+        let layout = Layout {
+            sequence_bits: 4,
+            resolution: Duration::from_millis(100),
+            ..Layout::DEFAULT
+        };
+        assert_eq!(layout.max_ids_per_second(), 160);
+        let clock = Arc::new(mock::ManualClock::default());
+        let generator = Generator::builder()
+            .layout(layout)
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let mut now = 1_000_000_000_000;
+        let mut run = |per_tick: usize| {
+            for _ in 0..10 {
+                clock.set(now);
+                for _ in 0..per_tick {
+                    generator.try_generate_sync(0).unwrap();
+                }
+                now += 100_000_000;
+            }
+            clock.set(now);
+            generator.try_generate_sync(0).unwrap();
+        };
+
+        // 100 per second, below 80%
+        run(10);
+        assert_eq!(generator.capacity_warnings(), 0);
+        // 140 per second
+        run(14);
+        assert_eq!(generator.capacity_warnings(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_batch_monotone_across_time() {
+        // Salting reverses the order of sequence ids 0 to 3
+        let build = || {
+            let mut readings = vec![1_000; 3];
+            readings.extend([2_000; 5]);
+            readings.push(3_000);
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").salt(Some(0x0300)).build())
+                .worker_id_source(WorkerIdSource::Static(1))
+                .clock(Arc::new(mock::ScriptedClock::new(readings)))
+                .build()
+                .unwrap()
+        };
+        let plain = build().generate_batch(0, 12).await;
+        assert!(plain.windows(2).any(|w| w[0] >= w[1]));
+
+        let batch = build().generate_batch_monotone_across_time(0, 12).await;
+        assert_eq!(batch.len(), 12);
+        assert!(batch.windows(2).all(|w| w[0] < w[1]));
+        assert!(batch.iter().any(|s| s.timestamp == 2_000));
+    }
+
+    #[test]
+    pub fn test_panic_location() {
+        use std::cell::Cell;
+        use std::panic::{self, AssertUnwindSafe};
+        thread_local! {
+            static CAPTURE: Cell<bool> = const { Cell::new(false) };
+            static LOCATION: Cell<Option<u32>> = const { Cell::new(None) };
+        }
+        // Only panics of this test are captured, others are reported as usual
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| match CAPTURE.with(|c| c.get()) {
+            true => LOCATION.with(|l| l.set(info.location().map(|l| l.line()))),
+            false => prev(info),
+        }));
+        let generator = Generator::new("http://127.0.0.1:1");
+        CAPTURE.with(|c| c.set(true));
+        let line = line!() + 1;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| generator.generate_sync(0)));
+        CAPTURE.with(|c| c.set(false));
+        assert!(result.is_err());
+        assert_eq!(LOCATION.with(|l| l.get()), Some(line));
+    }
+
+    #[test]
+    pub fn test_pod_name() {
+        for (name, id) in [
+            ("web-0", 0),
+            ("web-7", 7),
+            ("my-stateful-set-42", 42),
+            ("db-7\n", 7),
+        ] {
+            assert_eq!(
+                WorkerIdSource::from_pod_name(name).unwrap(),
+                WorkerIdSource::Static(id)
+            );
+        }
+        for name in ["web", "web-", "-7", "web-7a", "web-+7", "web-70000", ""] {
+            assert!(matches!(
+                WorkerIdSource::from_pod_name(name),
+                Err(SnowflakeError::InvalidPodName(_))
+            ));
+        }
+
+        let path = std::env::temp_dir().join(format!("snowflake-pod-name-{}", std::process::id()));
+        std::fs::write(&path, "web-3\n").unwrap();
+        let source = WorkerIdSource::from_pod_name_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(source, WorkerIdSource::Static(3));
+        assert!(WorkerIdSource::from_pod_name_file(&path).is_err());
+
+        // The ordinal has to be accepted by the config
+        let config = SnowflakeConfig::builder("").worker_id_range(0..=2).build();
+        assert!(Generator::builder()
+            .config(config)
+            .worker_id_source(source)
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_sequence_range() {
+        let build = |range| {
+            let mut readings = vec![1_000; 200];
+            readings.push(2_000);
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").sequence_range(range).build())
+                .worker_id_source(WorkerIdSource::Static(1))
+                .clock(Arc::new(mock::ScriptedClock::new(readings)))
+                .build()
+                .unwrap()
+        };
+        let (a, b) = (build(0..=127), build(128..=255));
+        assert_eq!(a.remaining_sequence(), 128);
+        let a: HashSet<_> = a.generate_batch(0, 150).await.into_iter().collect();
+        let b: HashSet<_> = b.generate_batch(0, 150).await.into_iter().collect();
+        assert!(a.iter().all(|s| s.sequence_id <= 127));
+        assert!(b.iter().all(|s| s.sequence_id >= 128));
+        assert!(a.iter().any(|s| s.timestamp == 2_000));
+        assert!(a.is_disjoint(&b));
+
+        assert!(matches!(
+            Generator::builder()
+                .config(
+                    SnowflakeConfig::builder("")
+                        .layout(Layout {
+                            sequence_bits: 4,
+                            ..Layout::DEFAULT
+                        })
+                        .sequence_range(20..=30)
+                        .build()
+                )
+                .build(),
+            Err(SnowflakeError::InvalidSequenceRange { .. })
+        ));
+    }
+
+    #[test]
+    pub fn test_on_worker_id_resolved() {
+        let server = mock::serve(|_| {
+            // Keep the first callers waiting on the claim
+            sleep(Duration::from_millis(100));
+            (200, mock::lease(7))
+        });
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let seen = fired.clone();
+        let generator = Arc::new(
+            Generator::builder()
+                .config(SnowflakeConfig::new(server.url.clone()))
+                .on_worker_id_resolved(move |id| seen.lock().unwrap().push(id))
+                .build()
+                .unwrap(),
+        );
+        let callers: Vec<_> = (0..16)
+            .map(|_| {
+                let generator = generator.clone();
+                std::thread::spawn(move || generator.worker_id().unwrap())
+            })
+            .collect();
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), 7);
+        }
+        assert_eq!(*fired.lock().unwrap(), vec![7]);
+
+        // Hooks registered after the claim fire right away
+        let late = Arc::new(Mutex::new(None));
+        let seen = late.clone();
+        generator.on_worker_id_resolved(move |id| *seen.lock().unwrap() = Some(id));
+        assert_eq!(*late.lock().unwrap(), Some(7));
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_into() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(3))
+            .build()
+            .unwrap();
+        let mut out = Snowflake {
+            timestamp: 0,
+            worker_id: 0,
+            sequence_id: 0,
+            usage_id: 0,
+        };
+        let mut seen = HashSet::new();
+        for _ in 0..100 {
+            generator.generate_into(2, &mut out).await;
+            assert_eq!((out.worker_id, out.usage_id), (3, 2));
+            assert!(out.timestamp > 0);
+            assert!(seen.insert(out));
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_standby() {
+        let claims = Arc::new(AtomicUsize::new(0));
+        let claimed = claims.clone();
+        let server = mock::serve(move |path| {
+            let id = match path {
+                "/" => 7 + claimed.fetch_add(1, Ordering::SeqCst) as crate::WorkerId,
+                "/reverify/7" => return (410, String::new()),
+                _ => 8,
+            };
+            // Re-verify a second after the claim
+            let lease = format!(
+                r#"{{"id":{},"ts":{},"re_ts":{}}}"#,
+                id,
+                mock::now_secs(),
+                mock::now_secs() + PRE_TIME + 1
+            );
+            (200, lease)
+        });
+        let generator =
+            Generator::with_config(SnowflakeConfig::builder(&server.url).standby(true).build());
+        assert_eq!(generator.generate(0).await.worker_id, 7);
+        let start = Instant::now();
+        while generator.generate(0).await.worker_id != 8 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "standby not promoted"
+            );
+            sleep(Duration::from_millis(20));
+        }
+        // No further claims were needed
+        assert_eq!(generator.generate_sync(0).worker_id, 8);
+        assert_eq!(claims.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_standby_out_of_range() {
+        let claims = Arc::new(AtomicUsize::new(0));
+        let claimed = claims.clone();
+        let server = mock::serve(move |path| {
+            let id = match path {
+                "/" => 7 + claimed.fetch_add(1, Ordering::SeqCst) as crate::WorkerId,
+                "/reverify/7" => return (410, String::new()),
+                _ => 8,
+            };
+            let lease = format!(
+                r#"{{"id":{},"ts":{},"re_ts":{}}}"#,
+                id,
+                mock::now_secs(),
+                mock::now_secs() + PRE_TIME + 1
+            );
+            (200, lease)
+        });
+        let config = SnowflakeConfig::builder(&server.url)
+            .standby(true)
+            .worker_id_range(0..=7)
+            .build();
+        let generator = Generator::with_config(config);
+        assert_eq!(generator.generate(0).await.worker_id, 7);
+        assert_eq!(claims.load(Ordering::SeqCst), 2);
+
+        // The spare is outside of the range, so it's never promoted
+        sleep(Duration::from_millis(1500));
+        assert!(!matches!(generator.try_generate(0).await, Ok(s) if s.worker_id == 8));
+    }
+
+    #[tokio::test]
+    pub async fn test_standby_long_poll_revoked() {
+        let claims = Arc::new(AtomicUsize::new(0));
+        let claimed = claims.clone();
+        let server = mock::serve(move |path| {
+            if path.starts_with("/poll/7?") {
+                // Revoked right away, possibly while the standby is still claimed
+                return (410, String::new());
+            }
+            if path.starts_with("/poll/") {
+                sleep(Duration::from_millis(200));
+                return (204, String::new());
+            }
+            let id = 7 + claimed.fetch_add(1, Ordering::SeqCst) as crate::WorkerId;
+            (200, mock::lease(id))
+        });
+        let config = SnowflakeConfig::builder(&server.url)
+            .standby(true)
+            .long_poll(Some(Duration::from_secs(30)))
+            .build();
+        let generator = Generator::with_config(config);
+        assert_eq!(generator.generate(0).await.worker_id, 7);
+        let start = Instant::now();
+        while generator.generate(0).await.worker_id != 8 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "standby not promoted"
+            );
+            sleep(Duration::from_millis(20));
+        }
+        assert_eq!(claims.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_with_metadata() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .layout(Layout {
+                sequence_bits: 1,
+                ..Layout::DEFAULT
+            })
+            .clock(Arc::new(mock::ScriptedClock::new(vec![
+                1_000, 1_000, 1_000, 1_001,
+            ])))
+            .build()
+            .unwrap();
+        let (snowflake, metadata) = generator.generate_with_metadata(0).await;
+        assert_eq!(snowflake.sequence_id, 0);
+        assert_eq!(
+            metadata,
+            GenerationMetadata {
+                waited: false,
+                sequence_id: 0,
+                timestamp_advanced: true,
+            }
+        );
+        let (snowflake, metadata) = generator.generate_with_metadata(0).await;
+        assert_eq!(snowflake.sequence_id, 1);
+        assert_eq!(
+            metadata,
+            GenerationMetadata {
+                waited: false,
+                sequence_id: 1,
+                timestamp_advanced: false,
+            }
+        );
+        // Sequence of the tick exhausted
+        let (snowflake, metadata) = generator.generate_with_metadata(0).await;
+        assert_eq!(snowflake.timestamp, 1_001);
+        assert_eq!(
+            metadata,
+            GenerationMetadata {
+                waited: true,
+                sequence_id: 0,
+                timestamp_advanced: true,
+            }
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_with_sequence() {
+        let layout = Layout {
+            sequence_bits: 4,
+            ..Layout::DEFAULT
+        };
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(4))
+            .layout(layout)
+            .build()
+            .unwrap();
+        let snowflake = generator
+            .generate_with_sequence(3, 1_700_000_000_000_000_000, 9)
+            .await
+            .unwrap();
+        assert_eq!(
+            layout.unpack(layout.pack(&snowflake)),
+            Snowflake {
+                timestamp: 1_700_000_000_000_000_000,
+                worker_id: 4,
+                sequence_id: 9,
+                usage_id: 3,
+            }
+        );
+        assert!(matches!(
+            generator.generate_with_sequence(3, 1_000, 16).await,
+            Err(SnowflakeError::Parse(ParseError::FieldOverflow {
+                field: "sequence id"
+            }))
+        ));
+
+        let db_keys = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(4))
+            .layout(Layout::DB_KEY)
+            .epoch(UNIX_EPOCH + Duration::from_nanos(Layout::DB_KEY.epoch as u64))
+            .build()
+            .unwrap();
+        let epoch = db_keys.layout().epoch;
+        assert_eq!(epoch, Layout::DB_KEY.epoch);
+        assert!(matches!(
+            db_keys
+                .generate_with_sequence(3, epoch - 1_000_000, 0)
+                .await,
+            Err(SnowflakeError::Parse(ParseError::FieldOverflow {
+                field: "timestamp"
+            }))
+        ));
+        assert!(matches!(
+            db_keys
+                .generate_with_sequence(3, epoch + 1_500_000, 0)
+                .await,
+            Err(SnowflakeError::Parse(ParseError::UnalignedTimestamp))
+        ));
+        let aligned = db_keys
+            .generate_with_sequence(3, epoch + 2_000_000, 0)
+            .await
+            .unwrap();
+        assert_eq!(aligned.timestamp, epoch + 2_000_000);
+    }
+
+    #[tokio::test]
+    pub async fn test_retrying_on_skew() {
+        let claims = AtomicUsize::new(0);
+        let server = mock::serve(move |_| {
+            // Coordinator an hour ahead on the first claim only
+            let ahead = if claims.fetch_add(1, Ordering::SeqCst) == 0 {
+                3600
+            } else {
+                0
+            };
+            let lease = format!(
+                r#"{{"id":5,"ts":{},"re_ts":{}}}"#,
+                mock::now_secs() + ahead,
+                mock::now_secs() + PRE_TIME + 3600
+            );
+            (200, lease)
+        });
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            max_total_duration: None,
+        };
+        let generator = Generator::with_config(
+            SnowflakeConfig::builder(&server.url)
+                .skew_retry(policy)
+                .build(),
+        );
+        let snowflake = generator.generate_retrying_on_skew(0).await.unwrap();
+        assert_eq!(snowflake.worker_id, 5);
+        assert_eq!(server.hits(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_generate_fallible_batch() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(2))
+            .clock(Arc::new(mock::ScriptedClock::new(vec![
+                1_000, 2_000, 3_000, 2_500, 4_000,
+            ])))
+            .build()
+            .unwrap();
+        match generator.generate_fallible_batch(0, 5).await {
+            Err(SnowflakeError::BatchFailed { generated, source }) => {
+                assert_eq!(generated, 3);
+                assert!(matches!(
+                    *source,
+                    SnowflakeError::ClockRegressed { by: 500 }
+                ));
+            }
+            other => panic!("Expected a failed batch, got {:?}", other),
+        }
+        let snowflakes = generator.generate_fallible_batch(0, 2).await.unwrap();
+        assert_eq!(snowflakes[0].timestamp, 4_000);
+        assert!(snowflakes[0] < snowflakes[1]);
+    }
+
+    #[tokio::test]
+    pub async fn test_adaptive_margin() {
+        let server = mock::serve(|path| {
+            if path.starts_with("/reverify/") {
+                sleep(Duration::from_millis(1500));
+            }
+            let lease = format!(
+                r#"{{"id":5,"ts":{},"re_ts":{}}}"#,
+                mock::now_secs(),
+                mock::now_secs() + PRE_TIME + 2
+            );
+            (200, lease)
+        });
+        let generator = Generator::new(&server.url);
+        generator.generate(0).await;
+        let base = Duration::from_secs(PRE_TIME);
+        assert_eq!(generator.reverify_state().margin, base);
+        let start = Instant::now();
+        while generator.reverify_state().last_success.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(6), "not re-verified");
+            sleep(Duration::from_millis(50));
+        }
+        assert!(generator.reverify_state().margin >= base + Duration::from_millis(1500));
+    }
+
+    #[tokio::test]
+    pub async fn test_sequence_reset_value() {
+        let generator = Generator::builder()
+            .config(SnowflakeConfig::builder("").sequence_reset_value(1).build())
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(Arc::new(mock::ScriptedClock::new(vec![
+                1_000, 1_000, 2_000, 2_000, 3_000,
+            ])))
+            .build()
+            .unwrap();
+        let ids: Vec<_> = generator
+            .generate_batch(0, 5)
+            .await
+            .iter()
+            .map(|s| (s.timestamp, s.sequence_id))
+            .collect();
+        assert_eq!(
+            ids,
+            [(1_000, 1), (1_000, 2), (2_000, 1), (2_000, 2), (3_000, 1)]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_priority() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let normal = generator.generate(5).await;
+        assert!(!generator.is_high_priority(&normal));
+        assert!(!generator.is_high_priority(&generator.generate_with_priority(5, false).await));
+        let high = generator.generate_with_priority(5, true).await;
+        assert_eq!(high.usage_id, 0x85);
+        assert!(generator.is_high_priority(&high));
+        assert!(generator.is_high_priority(&Snowflake::from_u128(high.to_u128())));
+
+        let low_bit = Generator::builder()
+            .config(SnowflakeConfig::builder("").priority_bit(0).build())
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        assert_eq!(low_bit.generate_with_priority(4, true).await.usage_id, 5);
+        assert!(matches!(
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").priority_bit(8).build())
+                .build(),
+            Err(SnowflakeError::InvalidLayout(_))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_current_rate() {
+        let clock = Arc::new(mock::ManualClock::default());
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let start = 1_000_000_000;
+        // 200 ids/s for two seconds
+        for i in 0..400 {
+            clock.set(start + i * 5_000_000);
+            generator.generate(0).await;
+        }
+        clock.set(start + 2_000_000_000);
+        let rate = generator.current_rate();
+        assert!((190.0..=210.0).contains(&rate), "{}", rate);
+        generator.generate_batch(0, 10).await;
+        clock.set(start + 3_000_000_000);
+        assert!(generator.current_rate() < rate);
+    }
+
+    #[tokio::test]
+    pub async fn test_drain() {
+        let generator = Arc::new(
+            Generator::builder()
+                .config(
+                    SnowflakeConfig::builder("")
+                        .rate_limit(Some(RateLimit {
+                            per_second: 10,
+                            burst: 1,
+                        }))
+                        .build(),
+                )
+                .worker_id_source(WorkerIdSource::Static(1))
+                .build()
+                .unwrap(),
+        );
+        // Rate limited, so they are in progress for up to 200ms
+        let in_flight: Vec<_> = (0..3)
+            .map(|_| {
+                let generator = generator.clone();
+                std::thread::spawn(move || generator.try_generate_sync(0))
+            })
+            .collect();
+        sleep(Duration::from_millis(50));
+        let start = Instant::now();
+        generator.begin_drain();
+        assert!(matches!(
+            generator.try_generate_sync(0),
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator.try_generate_n_nonblocking(0, 5).await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator.generate_with_sequence(0, 1_000, 1).await,
+            Err(SnowflakeError::Draining)
+        ));
+        generator.await_drained().await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        for handle in in_flight {
+            handle.join().unwrap().unwrap();
+        }
+        assert!(matches!(
+            generator.try_generate(0).await,
+            Err(SnowflakeError::Draining)
+        ));
+        generator.await_drained().await;
+    }
+
+    #[tokio::test]
+    pub async fn test_try_variants() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let (first, metadata) = generator.try_generate_with_metadata(0).await.unwrap();
+        assert!(!metadata.waited);
+        let bounded = generator.try_generate_bounded_sequence(0, 3).await.unwrap();
+        let affine = generator.try_generate_with_affinity(0, 7).await.unwrap();
+        let hinted = generator.try_generate_with_ts_hint(0, 0).await.unwrap();
+        let throttled = generator
+            .try_generate_throttled(0, Duration::from_millis(1))
+            .await
+            .unwrap();
+        let batch = generator
+            .try_generate_batch_monotone_across_time(0, 3)
+            .await
+            .unwrap();
+        assert!(first < bounded && bounded < hinted && hinted < throttled);
+        assert!(throttled < batch[0]);
+        assert_eq!(affine.worker_id, 1);
+
+        generator.begin_drain();
+        assert!(matches!(
+            generator.try_generate_with_metadata(0).await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator.try_generate_bounded_sequence(0, 3).await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator.try_generate_with_affinity(0, 7).await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator.try_generate_with_ts_hint(0, 0).await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator
+                .try_generate_throttled(0, Duration::from_millis(1))
+                .await,
+            Err(SnowflakeError::Draining)
+        ));
+        assert!(matches!(
+            generator
+                .try_generate_batch_monotone_across_time(0, 3)
+                .await,
+            Err(SnowflakeError::Draining)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_deadline_metadata() {
+        let generator = Generator::builder()
+            .config(
+                SnowflakeConfig::builder("")
+                    .rate_limit(Some(RateLimit {
+                        per_second: 10,
+                        burst: 1,
+                    }))
+                    .build(),
+            )
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let deadline = Duration::from_secs(10);
+        let (_, generous) = generator
+            .generate_with_deadline_metadata(0, deadline)
+            .await
+            .unwrap();
+        assert_eq!(generous.exceeded, Duration::ZERO);
+        assert_eq!(generous.remaining + generous.elapsed, deadline);
+        assert!(generous.remaining > Duration::from_secs(9));
+
+        // Throttled for about 100ms
+        let deadline = Duration::from_millis(10);
+        let (_, tight) = generator
+            .generate_with_deadline_metadata(0, deadline)
+            .await
+            .unwrap();
+        assert_eq!(tight.remaining, Duration::ZERO);
+        assert_eq!(tight.exceeded + deadline, tight.elapsed);
+        assert!(tight.exceeded >= Duration::from_millis(50), "{:?}", tight);
+    }
+
+    #[tokio::test]
+    pub async fn test_bounded_sequence() {
+        let clock = Arc::new(mock::ScriptedClock::new(vec![
+            1_000, 1_000, 1_000, 1_000, 1_000, 2_000,
+        ]));
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .clock(clock)
+            .build()
+            .unwrap();
+        let mut snowflakes = Vec::new();
+        for _ in 0..4 {
+            snowflakes.push(generator.generate_bounded_sequence(0, 3).await);
+        }
+        let fields: Vec<_> = snowflakes
+            .iter()
+            .map(|s| (s.timestamp, s.sequence_id))
+            .collect();
+        // Waited for the clock after 3 sequence ids, far below SequenceId::MAX
+        assert_eq!(fields, [(1_000, 0), (1_000, 1), (1_000, 2), (2_000, 0)]);
+        // The full range is back for other methods
+        assert_eq!(generator.generate(0).await.sequence_id, 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_affinity() {
+        assert!(matches!(
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").affinity_stripes(0).build())
+                .build(),
+            Err(SnowflakeError::InvalidAffinityStripes(0))
+        ));
+        let generator = Arc::new(
+            Generator::builder()
+                .config(SnowflakeConfig::builder("").affinity_stripes(4).build())
+                .worker_id_source(WorkerIdSource::Static(1))
+                .build()
+                .unwrap(),
+        );
+        let threads: Vec<_> = (0..8)
+            .map(|cpu| {
+                let generator = generator.clone();
+                std::thread::spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    (0..2_000)
+                        .map(|_| runtime.block_on(generator.generate_with_affinity(0, cpu)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut unique = HashSet::new();
+        for (cpu, thread) in threads.into_iter().enumerate() {
+            // 4 stripes of 64 sequence ids each
+            let stripe = (cpu % 4) as SequenceId * 64..=(cpu % 4) as SequenceId * 64 + 63;
+            for snowflake in thread.join().unwrap() {
+                assert!(stripe.contains(&snowflake.sequence_id), "{:?}", snowflake);
+                assert!(unique.insert(snowflake), "Duplicate {:?}", snowflake);
+            }
+        }
+        assert_eq!(unique.len(), 16_000);
+        // Generate shares the first stripe
+        assert!(generator.generate(0).await.sequence_id < 64);
+    }
+}
@@ -0,0 +1,116 @@
+//! Host-local mutual exclusion for worker ids
+//!
+//! Two processes on the same machine using the same worker id will generate colliding ids.
+//! The coordinator can't detect this when both processes reuse one claim, so a lock file per
+//! worker id in a host-local directory is locked for as long as the id is in use.
+//!
+//! The file is locked with an advisory os lock (`flock` on unix, `LockFileEx` on windows), which
+//! the os releases once the holder exits, also if it crashed. Left over lock files are reused
+
+use crate::{SnowflakeError, WorkerId};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::{env, process};
+
+/// Holds the host lock of a worker id, the lock is released on drop
+#[derive(Debug)]
+pub struct HostLock {
+    worker_id: WorkerId,
+    /// Locked file, closing it releases the lock
+    _file: File,
+}
+
+impl HostLock {
+    /// Acquires the host lock for `worker_id` in the default lock directory
+    /// (`SNOWFLAKE.HOST_LOCK_DIR` or the os temp dir)
+    pub fn acquire(worker_id: WorkerId) -> Result<HostLock, SnowflakeError> {
+        let dir = env::var("SNOWFLAKE.HOST_LOCK_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| env::temp_dir());
+        HostLock::acquire_in(&dir, worker_id)
+    }
+
+    /// Acquires the host lock for `worker_id` in `dir`
+    /// # Returns
+    /// * [SnowflakeError::WorkerIdInUse] - if a running process already holds the lock
+    pub fn acquire_in(dir: &Path, worker_id: WorkerId) -> Result<HostLock, SnowflakeError> {
+        let path = dir.join(format!("snowflake-worker-{}.lock", worker_id));
+        // Never removed, so every process locks the same file
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(SnowflakeError::WorkerIdInUse {
+                    worker_id,
+                    pid: holder(&mut file),
+                });
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", process::id())?;
+        file.flush()?;
+        Ok(HostLock {
+            worker_id,
+            _file: file,
+        })
+    }
+
+    /// Locked worker id
+    pub fn worker_id(&self) -> WorkerId {
+        self.worker_id
+    }
+}
+
+/// Pid written by the holder of the lock on `file`, 0 if it can't be read
+///
+/// Windows doesn't let other processes read a locked file
+fn holder(file: &mut File) -> u32 {
+    let mut pid = String::new();
+    match file.read_to_string(&mut pid) {
+        Ok(_) => pid.trim().parse().unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::host_lock::HostLock;
+    use crate::SnowflakeError;
+    use std::fs;
+
+    #[test]
+    pub fn test_conflict() {
+        let dir = std::env::temp_dir().join(format!("snowflake-lock-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = HostLock::acquire_in(&dir, 42).unwrap();
+        match HostLock::acquire_in(&dir, 42) {
+            Err(SnowflakeError::WorkerIdInUse { worker_id, pid }) => {
+                assert_eq!(worker_id, 42);
+                assert_eq!(pid, std::process::id());
+            }
+            v => panic!("Expected conflict, got {:?}", v),
+        }
+        drop(first);
+        assert!(HostLock::acquire_in(&dir, 42).is_ok());
+
+        // A lock file left by a crashed holder isn't locked anymore
+        fs::write(dir.join("snowflake-worker-43.lock"), "4194305").unwrap();
+        let lock = HostLock::acquire_in(&dir, 43).unwrap();
+        assert_eq!(lock.worker_id(), 43);
+        assert_eq!(
+            fs::read_to_string(dir.join("snowflake-worker-43.lock")).unwrap(),
+            std::process::id().to_string()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
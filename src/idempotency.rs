@@ -0,0 +1,90 @@
+//! Bounded LRU cache mapping idempotency keys to snowflakes
+
+use crate::{NanoTimestamp, Snowflake};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Bounds the idempotency keys remembered by [crate::Generator::generate_idempotent]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdempotencyCache {
+    /// Maximum keys remembered, the least recently used one is evicted first
+    pub capacity: usize,
+    /// How long a key maps to the same snowflake after it was minted
+    pub ttl: Duration,
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        IdempotencyCache {
+            capacity: 10_000,
+            ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    snowflake: Snowflake,
+    minted: NanoTimestamp,
+    last_use: u64,
+}
+
+/// Cache state, expiring from the generator clock
+#[derive(Debug)]
+pub(crate) struct KeyCache {
+    limit: IdempotencyCache,
+    entries: HashMap<String, Entry>,
+    /// Keys by last use, oldest first
+    lru: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl KeyCache {
+    pub(crate) fn new(limit: IdempotencyCache) -> Self {
+        KeyCache {
+            limit,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Snowflake minted for `key`, if it's neither expired nor evicted at `now`
+    pub(crate) fn get(&mut self, key: &str, now: NanoTimestamp) -> Option<Snowflake> {
+        let entry = self.entries.get_mut(key)?;
+        if now.saturating_sub(entry.minted) >= self.limit.ttl.as_nanos() {
+            self.lru.remove(&entry.last_use);
+            self.entries.remove(key);
+            return None;
+        }
+        self.uses += 1;
+        self.lru.remove(&entry.last_use);
+        entry.last_use = self.uses;
+        self.lru.insert(self.uses, key.to_string());
+        Some(entry.snowflake)
+    }
+
+    /// Remembers `snowflake` for `key`, evicting the least recently used keys above the capacity
+    pub(crate) fn insert(&mut self, key: &str, snowflake: Snowflake, now: NanoTimestamp) {
+        if self.limit.capacity == 0 {
+            return;
+        }
+        self.uses += 1;
+        let entry = Entry {
+            snowflake,
+            minted: now,
+            last_use: self.uses,
+        };
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.lru.remove(&old.last_use);
+        }
+        self.lru.insert(self.uses, key.to_string());
+        while self.entries.len() > self.limit.capacity {
+            match self.lru.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+}
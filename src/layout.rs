@@ -0,0 +1,483 @@
+//! Bit layout of packed snowflakes
+
+use crate::{
+    NanoTimestamp, ParseError, SequenceId, Snowflake, SnowflakeError, UsageId, WorkerId,
+    GLOBAL_CONFIG,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Field of a packed snowflake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LayoutField {
+    /// Ticks since the layout epoch
+    Timestamp,
+    /// Worker id
+    Worker,
+    /// Sequence id
+    Sequence,
+    /// Usage id
+    Usage,
+}
+
+/// Byte order of [Snowflake::to_bytes]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ByteOrder {
+    /// Most significant byte first, sorting bytewise like the snowflakes
+    #[default]
+    BigEndian,
+    /// Least significant byte first for interop, doesn't sort bytewise
+    LittleEndian,
+}
+
+/// Describes how a snowflake is packed into an u128
+///
+/// Fields are packed from the high to the low bits in `order`, by default timestamp, worker id,
+/// sequence id and usage id. The timestamp field counts ticks of `resolution` since `epoch`
+///
+/// Packed values and hex representations sort by the highest field first. With the timestamp
+/// highest they sort by generation time, with the worker or usage id highest they group by it
+/// (e.g. for range scans per shard) but only sort by time within a group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layout {
+    /// Width of the timestamp field
+    pub timestamp_bits: u8,
+    /// Width of the worker id field
+    pub worker_bits: u8,
+    /// Width of the sequence id field
+    pub sequence_bits: u8,
+    /// Width of the usage id field
+    pub usage_bits: u8,
+    /// Duration of one timestamp tick
+    pub resolution: Duration,
+    /// Start of the timestamp field as nano secs since unix epoch
+    pub epoch: NanoTimestamp,
+    /// Fields from the high to the low bits, each exactly once, see [Layout::with_order]
+    pub order: [LayoutField; 4],
+    /// Byte order of [Snowflake::to_bytes]
+    pub byte_order: ByteOrder,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::DEFAULT
+    }
+}
+
+/// Returns the biggest value fitting into `bits`
+const fn field_max(bits: u8) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// 2020-01-01 as nano secs since unix epoch, the epoch of [Layout::DB_KEY] and [Layout::COMPACT_96]
+const DB_KEY_EPOCH: NanoTimestamp = 1_577_836_800_000_000_000;
+
+impl Layout {
+    /// 63 bit database primary keys, see [Snowflake::new_db_key]
+    ///
+    /// 41 bit milli sec timestamp since 2020 (until 2089), 10 bit worker id, 8 bit sequence and
+    /// 4 bit usage id. The timestamp is highest, so keys increase and inserts append to the
+    /// right-most B-tree page instead of splitting pages all over the index. The sign bit stays
+    /// free, so keys are positive as `i64`
+    pub const DB_KEY: Layout = Layout {
+        timestamp_bits: 41,
+        worker_bits: 10,
+        sequence_bits: 8,
+        usage_bits: 4,
+        resolution: Duration::from_millis(1),
+        epoch: DB_KEY_EPOCH,
+        order: Layout::DEFAULT.order,
+        byte_order: ByteOrder::BigEndian,
+    };
+
+    /// 96 bit keys for stores without 128 bit columns, see [Snowflake::to_bytes_96]
+    ///
+    /// 64 bit micro sec timestamp since 2020, 16 bit worker id, 8 bit sequence and usage id. The
+    /// worker id and sequence already use the full width of their types, so a split like 48 bit
+    /// timestamp, 24 bit worker and 16 bit sequence can't be represented, and the timestamp takes
+    /// the rest. As wide as [Layout::DEFAULT], but it doesn't run out in 2554
+    pub const COMPACT_96: Layout = Layout {
+        timestamp_bits: 64,
+        worker_bits: 16,
+        sequence_bits: 8,
+        usage_bits: 8,
+        resolution: Duration::from_micros(1),
+        epoch: DB_KEY_EPOCH,
+        order: Layout::DEFAULT.order,
+        byte_order: ByteOrder::BigEndian,
+    };
+
+    /// 64 bit nano sec timestamp since unix epoch, 16 bit worker id, 8 bit sequence and usage id
+    pub const DEFAULT: Layout = Layout {
+        timestamp_bits: 64,
+        worker_bits: 16,
+        sequence_bits: 8,
+        usage_bits: 8,
+        resolution: Duration::from_nanos(1),
+        epoch: 0,
+        order: [
+            LayoutField::Timestamp,
+            LayoutField::Worker,
+            LayoutField::Sequence,
+            LayoutField::Usage,
+        ],
+        byte_order: ByteOrder::BigEndian,
+    };
+
+    /// Layout of the global generator, of the config of [Snowflake::init] if it was called
+    ///
+    /// [Layout::DEFAULT] otherwise, as for a generator configured from the environment. Doesn't
+    /// depend on whether the global generator started yet
+    pub fn active() -> Layout {
+        GLOBAL_CONFIG.get().map_or(Layout::DEFAULT, |c| c.layout)
+    }
+
+    /// Returns the layout with its epoch moved to `epoch`
+    pub fn with_epoch(mut self, epoch: SystemTime) -> Self {
+        self.epoch = epoch
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        self
+    }
+
+    /// Returns the layout with the timestamp shrunk to at most 63 bits in total
+    ///
+    /// The sign bit stays zero, so values of [Snowflake::to_i64_with] sort like the snowflakes in
+    /// signed 64 bit columns. Every bit taken halves the time range, [Layout::DB_KEY] already is
+    /// signed safe
+    pub fn signed_safe(mut self) -> Self {
+        let excess = self.total_bits().saturating_sub(63) as u8;
+        self.timestamp_bits = self.timestamp_bits.saturating_sub(excess);
+        self
+    }
+
+    /// Returns the layout with its fields packed in `order`, from the high to the low bits
+    ///
+    /// As active layout [Display](std::fmt::Display), [FromStr](std::str::FromStr), serde and
+    /// [Snowflake::to_bytes] follow the order, [Snowflake::canonical] and [Snowflake::to_u128]
+    /// always keep the default one. Keep the timestamp highest where ids have to sort by time
+    pub fn with_order(mut self, order: [LayoutField; 4]) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Width of `field`
+    fn bits(&self, field: LayoutField) -> u8 {
+        match field {
+            LayoutField::Timestamp => self.timestamp_bits,
+            LayoutField::Worker => self.worker_bits,
+            LayoutField::Sequence => self.sequence_bits,
+            LayoutField::Usage => self.usage_bits,
+        }
+    }
+
+    /// Shift of `field` in the packed form
+    fn shift(&self, field: LayoutField) -> u32 {
+        self.order
+            .iter()
+            .rev()
+            .take_while(|f| **f != field)
+            .map(|f| self.bits(*f) as u32)
+            .sum()
+    }
+
+    /// Value of `field` in `packed`
+    pub(crate) fn field(&self, packed: u128, field: LayoutField) -> u128 {
+        packed.checked_shr(self.shift(field)).unwrap_or(0) & field_max(self.bits(field))
+    }
+
+    /// Sum of all field widths
+    pub const fn total_bits(&self) -> u32 {
+        self.timestamp_bits as u32
+            + self.worker_bits as u32
+            + self.sequence_bits as u32
+            + self.usage_bits as u32
+    }
+
+    /// Number of hex digits needed for all fields
+    pub const fn hex_len(&self) -> usize {
+        (self.total_bits() as usize).div_ceil(4)
+    }
+
+    /// Number of bytes needed for all fields
+    pub const fn byte_len(&self) -> usize {
+        (self.total_bits() as usize).div_ceil(8)
+    }
+
+    /// Biggest timestamp field value, in ticks since epoch
+    pub fn max_ticks(&self) -> u128 {
+        field_max(self.timestamp_bits)
+    }
+
+    /// Biggest worker id fitting the layout
+    pub const fn max_worker_id(&self) -> WorkerId {
+        let max = field_max(self.worker_bits);
+        if max > WorkerId::MAX as u128 {
+            WorkerId::MAX
+        } else {
+            max as WorkerId
+        }
+    }
+
+    /// Biggest sequence id fitting the layout
+    pub const fn max_sequence_id(&self) -> SequenceId {
+        let max = field_max(self.sequence_bits);
+        if max > SequenceId::MAX as u128 {
+            SequenceId::MAX
+        } else {
+            max as SequenceId
+        }
+    }
+
+    /// Biggest usage id fitting the layout
+    pub const fn max_usage_id(&self) -> UsageId {
+        let max = field_max(self.usage_bits);
+        if max > UsageId::MAX as u128 {
+            UsageId::MAX
+        } else {
+            max as UsageId
+        }
+    }
+
+    /// Snowflakes a single worker can generate per second without waiting for the clock
+    pub fn max_ids_per_second(&self) -> u128 {
+        (self.max_sequence_id() as u128 + 1) * 1_000_000_000 / self.resolution_nanos()
+    }
+
+    /// Nano secs of one tick, at least 1
+    pub(crate) fn resolution_nanos(&self) -> NanoTimestamp {
+        self.resolution.as_nanos().max(1)
+    }
+
+    /// Errors if the fields don't fit into an u128 or exceed the width of their types
+    pub fn validate(&self) -> Result<(), SnowflakeError> {
+        let invalid = |reason: &str| Err(SnowflakeError::InvalidLayout(reason.to_string()));
+        if self.total_bits() > 128 {
+            return invalid("fields don't fit into 128 bits");
+        }
+        if self.timestamp_bits == 0 {
+            return invalid("timestamp field is empty");
+        }
+        if self.worker_bits as u32 > WorkerId::BITS {
+            return invalid("worker id field is wider then the worker id type");
+        }
+        if self.sequence_bits as u32 > SequenceId::BITS {
+            return invalid("sequence id field is wider then the sequence id type");
+        }
+        if self.usage_bits as u32 > UsageId::BITS {
+            return invalid("usage id field is wider then the usage id type");
+        }
+        if self.resolution.as_nanos() == 0 {
+            return invalid("resolution is zero");
+        }
+        // Unpacking the biggest timestamp field must stay within NanoTimestamp
+        let span = self.max_ticks().checked_mul(self.resolution_nanos());
+        if span.and_then(|span| span.checked_add(self.epoch)).is_none() {
+            return invalid("timestamps of the field don't fit into nano secs");
+        }
+        let fields = [
+            LayoutField::Timestamp,
+            LayoutField::Worker,
+            LayoutField::Sequence,
+            LayoutField::Usage,
+        ];
+        if !fields.iter().all(|f| self.order.contains(f)) {
+            return invalid("order doesn't contain every field once");
+        }
+        Ok(())
+    }
+
+    /// Converts a timestamp to ticks since epoch
+    pub(crate) fn ticks(&self, timestamp: NanoTimestamp) -> u128 {
+        timestamp.saturating_sub(self.epoch) / self.resolution_nanos()
+    }
+
+    /// Truncates a timestamp to the layout resolution
+    pub(crate) fn truncate(&self, timestamp: NanoTimestamp) -> NanoTimestamp {
+        self.epoch + self.ticks(timestamp) * self.resolution_nanos()
+    }
+
+    /// Packs `snowflake`, masking fields wider then the layout
+    pub fn pack(&self, snowflake: &Snowflake) -> u128 {
+        let usage_shift = self.shift(LayoutField::Usage);
+        let sequence_shift = self.shift(LayoutField::Sequence);
+        let worker_shift = self.shift(LayoutField::Worker);
+        let timestamp_shift = self.shift(LayoutField::Timestamp);
+        let field =
+            |v: u128, bits: u8, shift: u32| (v & field_max(bits)).checked_shl(shift).unwrap_or(0);
+        field(
+            self.ticks(snowflake.timestamp),
+            self.timestamp_bits,
+            timestamp_shift,
+        ) | field(snowflake.worker_id as u128, self.worker_bits, worker_shift)
+            | field(
+                snowflake.sequence_id as u128,
+                self.sequence_bits,
+                sequence_shift,
+            )
+            | field(snowflake.usage_id as u128, self.usage_bits, usage_shift)
+    }
+
+    /// Packs `snowflake`, erroring if a field doesn't fit its width
+    pub fn try_pack(&self, snowflake: &Snowflake) -> Result<u128, ParseError> {
+        let overflow = |field| Err(ParseError::FieldOverflow { field });
+        if snowflake.timestamp < self.epoch || self.ticks(snowflake.timestamp) > self.max_ticks() {
+            return overflow("timestamp");
+        }
+        if snowflake.worker_id > self.max_worker_id() {
+            return overflow("worker id");
+        }
+        if snowflake.sequence_id > self.max_sequence_id() {
+            return overflow("sequence id");
+        }
+        if snowflake.usage_id > self.max_usage_id() {
+            return overflow("usage id");
+        }
+        Ok(self.pack(snowflake))
+    }
+
+    /// Unpacks a snowflake created by [Layout::pack]
+    pub fn unpack(&self, v: u128) -> Snowflake {
+        Snowflake {
+            timestamp: self.epoch + self.field(v, LayoutField::Timestamp) * self.resolution_nanos(),
+            worker_id: self.field(v, LayoutField::Worker) as WorkerId,
+            sequence_id: self.field(v, LayoutField::Sequence) as SequenceId,
+            usage_id: self.field(v, LayoutField::Usage) as UsageId,
+        }
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::layout::{Layout, LayoutField};
+    use crate::{Generator, Snowflake, SnowflakeError, WorkerIdSource};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_default_matches_u128() {
+        let snowflake = Snowflake {
+            timestamp: 1_623_801_600_123_456_789,
+            worker_id: 513,
+            sequence_id: 7,
+            usage_id: 9,
+        };
+        assert_eq!(Layout::DEFAULT.pack(&snowflake), snowflake.to_u128());
+        assert_eq!(Layout::DEFAULT.unpack(snowflake.to_u128()), snowflake);
+    }
+
+    #[test]
+    pub fn test_custom_round_trip() {
+        let layout = Layout {
+            timestamp_bits: 41,
+            worker_bits: 10,
+            sequence_bits: 4,
+            usage_bits: 8,
+            resolution: Duration::from_millis(1),
+            epoch: 1_600_000_000_000_000_000,
+            ..Layout::DEFAULT
+        };
+        layout.validate().unwrap();
+        let snowflake = Snowflake {
+            timestamp: 1_623_801_600_123_000_000,
+            worker_id: 1000,
+            sequence_id: 15,
+            usage_id: 200,
+        };
+        let packed = layout.pack(&snowflake);
+        assert!(packed < 1 << layout.total_bits());
+        assert_eq!(layout.unpack(packed), snowflake);
+    }
+
+    #[test]
+    pub fn test_timestamp_range() {
+        let layout = Layout {
+            timestamp_bits: 104,
+            worker_bits: 16,
+            sequence_bits: 8,
+            usage_bits: 0,
+            resolution: Duration::from_secs(1),
+            ..Layout::DEFAULT
+        };
+        assert!(matches!(
+            layout.validate(),
+            Err(SnowflakeError::InvalidLayout(_))
+        ));
+        let late_epoch = Layout {
+            epoch: u128::MAX - 1,
+            ..Layout::DEFAULT
+        };
+        assert!(late_epoch.validate().is_err());
+        let fitting = Layout {
+            timestamp_bits: 96,
+            ..layout
+        };
+        fitting.validate().unwrap();
+        let max = fitting.unpack(u128::MAX);
+        assert_eq!(fitting.truncate(max.timestamp), max.timestamp);
+    }
+
+    #[test]
+    pub fn test_reordered() {
+        let layout = Layout::DEFAULT.with_order([
+            LayoutField::Usage,
+            LayoutField::Timestamp,
+            LayoutField::Sequence,
+            LayoutField::Worker,
+        ]);
+        layout.validate().unwrap();
+        let snowflake = Snowflake {
+            timestamp: 0x0123_4567_89ab_cdef,
+            worker_id: 0xbeef,
+            sequence_id: 0x42,
+            usage_id: 0xa5,
+        };
+        let hex = snowflake.try_to_hex_with(&layout).unwrap();
+        assert_eq!(hex, "a50123456789abcdef42beef");
+        assert_eq!(Snowflake::from_hex_with(&hex, &layout).unwrap(), snowflake);
+        assert_eq!(layout.unpack(layout.pack(&snowflake)), snowflake);
+
+        let duplicate = Layout::DEFAULT.with_order([LayoutField::Timestamp; 4]);
+        assert!(duplicate.validate().is_err());
+        let bytes = snowflake.to_bytes_with(&layout);
+        assert_eq!(bytes[0], 0xa5);
+        assert_eq!(Snowflake::from_bytes_with(&bytes, &layout), Ok(snowflake));
+        // The canonical form keeps the default order whatever the layout
+        assert_eq!(snowflake.canonical(), "0123456789abcdefbeef42a5");
+    }
+
+    #[tokio::test]
+    pub async fn test_reordered_generator() {
+        let layout = Layout::DEFAULT.with_order([
+            LayoutField::Worker,
+            LayoutField::Timestamp,
+            LayoutField::Sequence,
+            LayoutField::Usage,
+        ]);
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(0x0102))
+            .layout(layout)
+            .build()
+            .unwrap();
+        let snowflakes = generator.generate_batch(7, 100).await;
+        let hexes: Vec<_> = snowflakes
+            .iter()
+            .map(|s| s.try_to_hex_with(generator.layout()).unwrap())
+            .collect();
+        for (snowflake, hex) in snowflakes.iter().zip(&hexes) {
+            assert!(hex.starts_with("0102"), "{}", hex);
+            assert!(hex.ends_with("07"), "{}", hex);
+            assert_eq!(
+                Snowflake::from_hex_with(hex, generator.layout()).unwrap(),
+                *snowflake
+            );
+        }
+        // Within a worker ids still sort by time
+        assert!(hexes.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,95 @@
+//! Exponentially weighted generation rate, see [crate::Generator::current_rate]
+
+use crate::NanoTimestamp;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Nano secs after which an earlier rate weighs 1 / e, the meter smooths over about this long
+const TAU: f64 = 5_000_000_000.0;
+
+/// Counts snowflakes with a single atomic add, folding them into the rate when it's read
+#[derive(Debug)]
+pub(crate) struct RateMeter {
+    /// Snowflakes counted since the last fold
+    count: AtomicU64,
+    /// Clock reading of the last fold or the first count, 0 until something is counted
+    since: AtomicU64,
+    /// Bits of the rate in snowflakes per second, NaN until the first fold
+    rate: AtomicU64,
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter {
+            count: AtomicU64::new(0),
+            since: AtomicU64::new(0),
+            rate: AtomicU64::new(f64::NAN.to_bits()),
+        }
+    }
+}
+
+impl RateMeter {
+    /// Counts `n` snowflakes generated at `now`, which only matters for the first count
+    #[inline]
+    pub(crate) fn record(&self, n: usize, now: NanoTimestamp) {
+        if self.since.load(Ordering::Relaxed) == 0 {
+            let _ =
+                self.since
+                    .compare_exchange(0, now as u64, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Snowflakes per second, folding the counts since the last read in
+    ///
+    /// The first read takes the plain average since the first count
+    pub(crate) fn rate(&self, now: NanoTimestamp) -> f64 {
+        let now = now as u64;
+        let since = self.since.load(Ordering::Relaxed);
+        let previous = f64::from_bits(self.rate.load(Ordering::Relaxed));
+        if since == 0 || now <= since {
+            return if previous.is_nan() { 0.0 } else { previous };
+        }
+        if self
+            .since
+            .compare_exchange(since, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_err()
+        {
+            // Folded concurrently
+            return f64::from_bits(self.rate.load(Ordering::Relaxed)).max(0.0);
+        }
+        let elapsed = (now - since) as f64;
+        let current = self.count.swap(0, Ordering::Relaxed) as f64 * 1e9 / elapsed;
+        let rate = if previous.is_nan() {
+            current
+        } else {
+            let weight = (-elapsed / TAU).exp();
+            previous * weight + current * (1.0 - weight)
+        };
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+        rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::meter::RateMeter;
+
+    #[test]
+    pub fn test_rate() {
+        let meter = RateMeter::default();
+        assert_eq!(meter.rate(1_000), 0.0);
+        for i in 0..100 {
+            meter.record(10, 1_000_000_000 + i * 10_000_000);
+        }
+        // 1000 snowflakes in a second
+        assert_eq!(meter.rate(2_000_000_000), 1000.0);
+        // Idle for a TAU
+        let idle = meter.rate(7_000_000_000);
+        assert!(
+            (idle - 1000.0 / std::f64::consts::E).abs() < 1e-6,
+            "{}",
+            idle
+        );
+        assert_eq!(meter.rate(7_000_000_000), idle);
+    }
+}
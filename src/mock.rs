@@ -0,0 +1,189 @@
+//! Minimal http coordinator used by tests
+
+use crate::clock::Clock;
+use crate::{CoordinatorTimestamp, NanoTimestamp, WorkerId, PRE_TIME};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Running mock coordinator
+pub struct MockCoordinator {
+    /// Base url of the coordinator
+    pub url: String,
+    hits: Arc<AtomicUsize>,
+}
+
+impl MockCoordinator {
+    /// Number of requests served so far
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+/// Serves `handler(path) -> (status, body)` on a random local port
+pub fn serve<F>(handler: F) -> MockCoordinator
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Couldn't bind mock coordinator");
+    let url = format!(
+        "http://{}",
+        listener
+            .local_addr()
+            .expect("Mock coordinator has no address")
+    );
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counter = hits.clone();
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let counter = counter.clone();
+            let handler = handler.clone();
+            // Connections are served concurrently, so slow handlers can overlap
+            thread::spawn(move || respond(stream, &counter, &*handler));
+        }
+    });
+    MockCoordinator { url, hits }
+}
+
+/// Answers a single request on `stream`
+fn respond<F>(mut stream: TcpStream, counter: &AtomicUsize, handler: &F)
+where
+    F: Fn(&str) -> (u16, String),
+{
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(s) => s,
+        Err(_) => return,
+    });
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut line = String::new();
+    while reader.read_line(&mut line).map(|n| n > 2).unwrap_or(false) {
+        line.clear();
+    }
+    let path = request_line.split(' ').nth(1).unwrap_or("/").to_string();
+    counter.fetch_add(1, Ordering::SeqCst);
+    let (status, body) = handler(&path);
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+/// Local unix time in seconds
+pub fn now_secs() -> CoordinatorTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// Coordinator response body handing out `id` with a lease of an hour
+pub fn lease(id: WorkerId) -> String {
+    format!(
+        r#"{{"id":{},"ts":{},"re_ts":{}}}"#,
+        id,
+        now_secs(),
+        now_secs() + PRE_TIME + 3600
+    )
+}
+
+/// Points `SNOWFLAKE.COORDINATOR` at a mock coordinator for tests using the global generator
+pub fn init_global() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let coordinator = serve(|_| (200, lease(1)));
+        std::env::set_var("SNOWFLAKE.COORDINATOR", &coordinator.url);
+    });
+}
+
+/// Clock advancing by one nano sec every `reads_per_tick` reads
+#[derive(Debug)]
+pub struct StepClock {
+    reads_per_tick: u64,
+    state: Mutex<(NanoTimestamp, u64)>,
+}
+
+impl StepClock {
+    /// Starts at `start` nano secs
+    pub fn new(start: NanoTimestamp, reads_per_tick: u64) -> Self {
+        StepClock {
+            reads_per_tick,
+            state: Mutex::new((start, 0)),
+        }
+    }
+}
+
+impl Clock for StepClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        let mut state = self.state.lock().expect("Couldn't lock StepClock");
+        state.1 += 1;
+        if state.1 > self.reads_per_tick {
+            state.0 += 1;
+            state.1 = 1;
+        }
+        state.0
+    }
+}
+
+/// Clock standing still until it's set
+#[derive(Debug, Default)]
+pub struct ManualClock(Mutex<NanoTimestamp>);
+
+impl ManualClock {
+    pub fn set(&self, now: NanoTimestamp) {
+        *self.0.lock().expect("Couldn't lock ManualClock") = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        *self.0.lock().expect("Couldn't lock ManualClock")
+    }
+}
+
+/// Clock returning the given readings in order, repeating the last one
+#[derive(Debug)]
+pub struct ScriptedClock {
+    readings: Mutex<VecDeque<NanoTimestamp>>,
+    reads: AtomicUsize,
+}
+
+impl ScriptedClock {
+    pub fn new(readings: Vec<NanoTimestamp>) -> Self {
+        ScriptedClock {
+            readings: Mutex::new(readings.into()),
+            reads: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of readings taken so far
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
+    }
+}
+
+impl Clock for ScriptedClock {
+    fn now_nanos(&self) -> NanoTimestamp {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        let mut readings = self.readings.lock().expect("Couldn't lock ScriptedClock");
+        if readings.len() > 1 {
+            readings.pop_front().unwrap_or_default()
+        } else {
+            readings.front().copied().unwrap_or_default()
+        }
+    }
+}
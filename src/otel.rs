@@ -0,0 +1,161 @@
+//! OpenTelemetry instruments, recording only with the ```otel``` feature
+
+#[cfg(feature = "otel")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "otel")]
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use std::time::Duration;
+
+/// Instruments installed by [init]
+#[cfg(feature = "otel")]
+struct Instruments {
+    generated: Counter<u64>,
+    coordinator_latency: Histogram<f64>,
+    reverify_failures: Counter<u64>,
+    failovers: Counter<u64>,
+}
+
+#[cfg(feature = "otel")]
+static INSTRUMENTS: OnceCell<Instruments> = OnceCell::new();
+
+/// Creates the instruments on `meter`, later calls are ignored
+#[cfg(feature = "otel")]
+pub(crate) fn init(meter: &Meter) {
+    INSTRUMENTS.get_or_init(|| Instruments {
+        generated: meter
+            .u64_counter("snowflake.generated")
+            .with_description("Generated snowflakes")
+            .build(),
+        coordinator_latency: meter
+            .f64_histogram("snowflake.coordinator.latency")
+            .with_description("Duration of coordinator requests")
+            .with_unit("s")
+            .build(),
+        reverify_failures: meter
+            .u64_counter("snowflake.reverify.failures")
+            .with_description("Failed worker id re-verify requests")
+            .build(),
+        failovers: meter
+            .u64_counter("snowflake.failovers")
+            .with_description("Switches to the fallback region")
+            .build(),
+    });
+}
+
+/// Counts `n` generated snowflakes
+#[inline]
+pub(crate) fn generated(n: usize) {
+    #[cfg(feature = "otel")]
+    if let Some(instruments) = INSTRUMENTS.get() {
+        instruments.generated.add(n as u64, &[]);
+    }
+    let _ = n;
+}
+
+/// Records the duration of a coordinator request
+#[inline]
+pub(crate) fn coordinator_latency(duration: Duration) {
+    #[cfg(feature = "otel")]
+    if let Some(instruments) = INSTRUMENTS.get() {
+        instruments
+            .coordinator_latency
+            .record(duration.as_secs_f64(), &[]);
+    }
+    let _ = duration;
+}
+
+/// Counts a failed re-verify request
+#[cfg(not(target_arch = "wasm32"))]
+#[inline]
+pub(crate) fn reverify_failed() {
+    #[cfg(feature = "otel")]
+    if let Some(instruments) = INSTRUMENTS.get() {
+        instruments.reverify_failures.add(1, &[]);
+    }
+}
+
+/// Counts a switch from region `from` to region `to`
+#[inline]
+pub(crate) fn failover(from: &str, to: &str) {
+    #[cfg(feature = "otel")]
+    if let Some(instruments) = INSTRUMENTS.get() {
+        instruments.failovers.add(
+            1,
+            &[
+                opentelemetry::KeyValue::new("from", from.to_string()),
+                opentelemetry::KeyValue::new("to", to.to_string()),
+            ],
+        );
+    }
+    let _ = (from, to);
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(all(test, feature = "otel"))]
+mod tests {
+    use crate::{mock, Generator, Snowflake, SnowflakeConfig, WorkerIdSource};
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::data::ResourceMetrics;
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{
+        InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+    };
+    use opentelemetry_sdk::Resource;
+    use std::sync::{Arc, Weak};
+
+    /// Lets the test collect from a reader owned by the provider
+    #[derive(Debug, Clone)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+
+        fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+            self.0.collect(rm)
+        }
+
+        fn force_flush(&self) -> MetricResult<()> {
+            self.0.force_flush()
+        }
+
+        fn shutdown(&self) -> MetricResult<()> {
+            self.0.shutdown()
+        }
+
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_instruments() {
+        let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        Snowflake::init_otel(&provider.meter("snowflake"));
+
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        generator.generate_batch(0, 3).await;
+        let coordinator = mock::serve(|_| (200, format!(r#"{{"ts":{}}}"#, mock::now_secs())));
+        Snowflake::measure_skew(&SnowflakeConfig::new(&coordinator.url)).unwrap();
+
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics).unwrap();
+        let names: Vec<_> = metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|s| s.metrics.iter().map(|m| m.name.to_string()))
+            .collect();
+        assert!(names.contains(&"snowflake.generated".to_string()));
+        assert!(names.contains(&"snowflake.coordinator.latency".to_string()));
+    }
+}
@@ -0,0 +1,168 @@
+//! Pre-generated snowflakes for latency critical paths
+
+use crate::{Generator, Snowflake, SnowflakeError, UsageId};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// Buffer of pre-generated snowflakes, topped up by a background thread
+///
+/// Snowflakes are generated in micro-batches, so taking one only pops from a buffer. Buffered
+/// snowflakes age, the pool hands out none older then its maximum age
+#[derive(Debug)]
+pub struct IdPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    generator: Arc<Generator>,
+    usage_id: UsageId,
+    capacity: usize,
+    max_age: Duration,
+    buffer: Mutex<VecDeque<Snowflake>>,
+}
+
+impl IdPool {
+    /// Starts a pool keeping up to `capacity` snowflakes of `generator` with `usage_id`
+    ///
+    /// The background thread stops once the pool is dropped
+    pub fn new(
+        generator: Arc<Generator>,
+        usage_id: UsageId,
+        capacity: usize,
+        max_age: Duration,
+    ) -> Self {
+        let inner = Arc::new(Inner {
+            generator,
+            usage_id,
+            capacity: capacity.max(1),
+            max_age,
+            buffer: Mutex::new(VecDeque::with_capacity(capacity)),
+        });
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || refill(weak));
+        IdPool { inner }
+    }
+
+    /// Takes the oldest buffered snowflake
+    ///
+    /// Errors with [SnowflakeError::TooOld] if it's older then the maximum age, dropping all stale
+    /// snowflakes, and with [SnowflakeError::PoolEmpty] if the refill can't keep up
+    pub fn take(&self) -> Result<Snowflake, SnowflakeError> {
+        let mut buffer = self.inner.buffer.lock().expect("Couldn't lock id pool");
+        let snowflake = buffer.pop_front().ok_or(SnowflakeError::PoolEmpty)?;
+        let age = self.inner.age(&snowflake);
+        if age > self.inner.max_age {
+            self.inner.drop_stale(&mut buffer);
+            return Err(SnowflakeError::TooOld {
+                age,
+                max_age: self.inner.max_age,
+            });
+        }
+        Ok(snowflake)
+    }
+
+    /// Number of buffered snowflakes
+    pub fn len(&self) -> usize {
+        self.inner
+            .buffer
+            .lock()
+            .expect("Couldn't lock id pool")
+            .len()
+    }
+
+    /// Returns if no snowflake is buffered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Inner {
+    /// Age of `snowflake` according to the generator clock
+    fn age(&self, snowflake: &Snowflake) -> Duration {
+        let nanos = self
+            .generator
+            .now_nanos()
+            .saturating_sub(snowflake.timestamp);
+        Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+    }
+
+    /// Drops the snowflakes older then the maximum age, which are at the front
+    fn drop_stale(&self, buffer: &mut VecDeque<Snowflake>) {
+        while buffer.front().is_some_and(|s| self.age(s) > self.max_age) {
+            buffer.pop_front();
+        }
+    }
+}
+
+/// Keeps the pool topped up until it's dropped
+fn refill(pool: Weak<Inner>) {
+    while let Some(inner) = pool.upgrade() {
+        let missing = {
+            let mut buffer = inner.buffer.lock().expect("Couldn't lock id pool");
+            inner.drop_stale(&mut buffer);
+            inner.capacity - buffer.len()
+        };
+        // Refills from half empty, so batches stay big enough to amortize the generator lock
+        if missing >= inner.capacity / 2 {
+            let batch = inner
+                .generator
+                .worker_id()
+                .and_then(|id| inner.generator.next_batch(id, inner.usage_id, missing));
+            match batch {
+                Ok(batch) => inner
+                    .buffer
+                    .lock()
+                    .expect("Couldn't lock id pool")
+                    .extend(batch),
+                Err(e) => log::warn!("Couldn't refill id pool: {}", e),
+            }
+        }
+        let interval = inner.max_age / 4;
+        drop(inner);
+        thread::sleep(interval.max(Duration::from_micros(100)));
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::{Generator, IdPool, SnowflakeError, WorkerIdSource};
+    use std::sync::Arc;
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    pub fn test_pool() {
+        let generator = Generator::builder()
+            .worker_id_source(WorkerIdSource::Static(1))
+            .build()
+            .unwrap();
+        let max_age = Duration::from_millis(40);
+        let pool = IdPool::new(Arc::new(generator), 3, 64, max_age);
+        sleep(Duration::from_millis(20));
+        assert!(!pool.is_empty());
+
+        let mut taken = Vec::new();
+        for _ in 0..200 {
+            match pool.take() {
+                Ok(snowflake) => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                    assert!(now.as_nanos() - snowflake.timestamp <= max_age.as_nanos());
+                    assert_eq!(snowflake.usage_id, 3);
+                    taken.push(snowflake);
+                }
+                Err(SnowflakeError::PoolEmpty) | Err(SnowflakeError::TooOld { .. }) => {}
+                Err(e) => panic!("{}", e),
+            }
+            sleep(Duration::from_micros(200));
+        }
+        assert!(taken.len() >= 64);
+        let count = taken.len();
+        taken.sort_unstable();
+        taken.dedup();
+        assert_eq!(taken.len(), count);
+    }
+}
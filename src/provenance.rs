@@ -0,0 +1,32 @@
+//! Recent snowflakes with the cause they were generated for, with the ```debug-provenance``` feature
+
+use crate::Snowflake;
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Causes kept before the oldest is dropped
+const CAPACITY: usize = 4096;
+
+static RECENT: Lazy<Mutex<VecDeque<(Snowflake, String)>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(CAPACITY)));
+
+/// Remembers `cause` for `snowflake`, dropping the oldest cause if full
+pub(crate) fn record(snowflake: Snowflake, cause: &str) {
+    let mut recent = RECENT.lock().expect("Couldn't lock provenance");
+    if recent.len() == CAPACITY {
+        recent.pop_front();
+    }
+    recent.push_back((snowflake, cause.to_string()));
+}
+
+/// Cause of `snowflake` if it's among the recent ones
+pub(crate) fn cause_of(snowflake: &Snowflake) -> Option<String> {
+    RECENT
+        .lock()
+        .expect("Couldn't lock provenance")
+        .iter()
+        .rev()
+        .find(|(s, _)| s == snowflake)
+        .map(|(_, cause)| cause.clone())
+}
@@ -0,0 +1,55 @@
+//! Token bucket limiting the generation rate
+
+use crate::NanoTimestamp;
+use serde::{Deserialize, Serialize};
+
+/// Nano secs per second
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Caps how many snowflakes a [crate::Generator] hands out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Tokens added per second
+    pub per_second: u32,
+    /// Maximum tokens saved up, generated at once after an idle period
+    pub burst: u32,
+}
+
+/// Token bucket state, refilled from the generator clock
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    /// Available tokens, in units of 1 / [NANOS_PER_SEC] tokens to avoid rounding
+    tokens: u128,
+    last_refill: Option<NanoTimestamp>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst as u128 * NANOS_PER_SEC,
+            last_refill: None,
+        }
+    }
+
+    /// Takes a token if one is available at `now`
+    /// # Returns
+    /// * bool - if a token was taken
+    pub(crate) fn try_take(&mut self, now: NanoTimestamp) -> bool {
+        let elapsed = self.last_refill.map_or(0, |last| now.saturating_sub(last));
+        self.last_refill = Some(now);
+        self.tokens = (self.tokens + elapsed * self.limit.per_second as u128)
+            .min(self.limit.burst as u128 * NANOS_PER_SEC);
+        if self.tokens < NANOS_PER_SEC {
+            return false;
+        }
+        self.tokens -= NANOS_PER_SEC;
+        true
+    }
+
+    /// Returns a token taken by [TokenBucket::try_take] but not used
+    pub(crate) fn give_back(&mut self) {
+        self.tokens = (self.tokens + NANOS_PER_SEC).min(self.limit.burst as u128 * NANOS_PER_SEC);
+    }
+}
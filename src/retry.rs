@@ -0,0 +1,49 @@
+//! Retry policy for coordinator requests
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Controls how often and how patiently a coordinator request is retried
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Maximum attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between two attempts
+    pub max_backoff: Duration,
+    /// Upper bound for the time spent retrying, regardless of the attempts left, unbounded if unset
+    pub max_total_duration: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            max_total_duration: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay after the failed attempt `attempt` (starting at 0)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+
+    /// Returns if the failed attempt `attempt` (starting at 0) is retried, `elapsed` after the first
+    ///
+    /// No retry is made if attempts are exhausted or waiting the backoff exceeds `max_total_duration`
+    pub fn should_retry(&self, attempt: u32, elapsed: Duration) -> bool {
+        attempt + 1 < self.max_attempts
+            && self
+                .max_total_duration
+                .is_none_or(|max| elapsed + self.backoff(attempt) <= max)
+    }
+}
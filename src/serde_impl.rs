@@ -0,0 +1,86 @@
+//! Serializes snowflakes as hex string for human-readable formats and as u128 otherwise
+//!
+//! Both are packed in [crate::Layout::active] like [std::fmt::Display], so only processes with the
+//! same active layout read each others snowflakes
+
+use crate::{Layout, Snowflake};
+use serde::de::{Error, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fmt::Formatter;
+
+impl Serialize for Snowflake {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_u128(Layout::active().pack(self))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Snowflake {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(SnowflakeVisitor)
+        } else {
+            deserializer.deserialize_u128(SnowflakeVisitor)
+        }
+    }
+}
+
+struct SnowflakeVisitor;
+
+impl<'de> Visitor<'de> for SnowflakeVisitor {
+    type Value = Snowflake;
+
+    fn expecting(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "a snowflake hex string or u128")
+    }
+
+    fn visit_str<E: Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_u128<E: Error>(self, v: u128) -> Result<Self::Value, E> {
+        // Bits beyond the layout would be masked, e.g. of corrupt input
+        Snowflake::from_packed(v, &Layout::active()).map_err(E::custom)
+    }
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::Snowflake;
+
+    fn snowflake() -> Snowflake {
+        Snowflake {
+            timestamp: 1_623_801_600_123_456_789,
+            worker_id: 513,
+            sequence_id: 7,
+            usage_id: 9,
+        }
+    }
+
+    #[test]
+    pub fn test_json_string() {
+        let json = serde_json::to_string(&snowflake()).unwrap();
+        assert_eq!(json, format!("\"{}\"", snowflake()));
+        assert_eq!(
+            serde_json::from_str::<Snowflake>(&json).unwrap(),
+            snowflake()
+        );
+    }
+
+    #[test]
+    pub fn test_postcard_bytes() {
+        let bytes = postcard::to_allocvec(&snowflake()).unwrap();
+        assert!(bytes.len() <= 16);
+        assert_eq!(
+            postcard::from_bytes::<Snowflake>(&bytes).unwrap(),
+            snowflake()
+        );
+        let too_wide = postcard::to_allocvec(&(1u128 << 96)).unwrap();
+        assert!(postcard::from_bytes::<Snowflake>(&too_wide).is_err());
+    }
+}
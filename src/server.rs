@@ -0,0 +1,220 @@
+//! Minimal reference coordinator over http, to get started and test end-to-end
+//!
+//! Leases the worker ids of a range in memory, so they're all free again after a restart. Run a
+//! single instance for development and tests, not as production coordinator
+//!
+//! | Request              | Response                                                        |
+//! |----------------------|-----------------------------------------------------------------|
+//! | `GET /`              | `{"id", "ts", "re_ts"}` of a free worker id, 503 if none is free |
+//! | `GET /reverify/{id}` | renewed lease, 410 if it isn't leased or already expired         |
+//! | `GET /release/{id}`  | 204, the worker id is free again                                 |
+//! | `GET /time`          | `{"ts"}` for the clock skew check                                |
+//!
+//! Leases aren't bound to a holder, a re-verify renews whoever holds the worker id
+
+use crate::{CoordinatorTimestamp, WorkerId, PRE_TIME};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds a lease is valid for, every re-verify renews it
+pub const LEASE_SECS: u64 = PRE_TIME + 600;
+
+/// Leases handed out by a [Server]
+#[derive(Debug)]
+struct Leases {
+    id_range: RangeInclusive<WorkerId>,
+    /// Unix time in seconds each leased worker id expires at
+    expiry: BTreeMap<WorkerId, CoordinatorTimestamp>,
+}
+
+impl Leases {
+    /// Leases the lowest free or expired worker id, returning it with its expiry
+    fn claim(&mut self, now: CoordinatorTimestamp) -> Option<(WorkerId, CoordinatorTimestamp)> {
+        let id = self
+            .id_range
+            .clone()
+            .find(|id| !matches!(self.expiry.get(id), Some(re_ts) if *re_ts >= now))?;
+        let re_ts = now + LEASE_SECS;
+        self.expiry.insert(id, re_ts);
+        Some((id, re_ts))
+    }
+
+    /// Renews the lease of `id`, `None` if it isn't leased or already expired
+    fn reverify(
+        &mut self,
+        id: WorkerId,
+        now: CoordinatorTimestamp,
+    ) -> Option<CoordinatorTimestamp> {
+        let re_ts = self.expiry.get_mut(&id).filter(|re_ts| **re_ts >= now)?;
+        *re_ts = now + LEASE_SECS;
+        Some(*re_ts)
+    }
+
+    /// Frees `id`, leased or not
+    fn release(&mut self, id: WorkerId) {
+        self.expiry.remove(&id);
+    }
+
+    /// Status and body answering a request of `path`
+    fn handle(&mut self, path: &str, now: CoordinatorTimestamp) -> (u16, String) {
+        let lease = |id, re_ts| format!(r#"{{"id":{},"ts":{},"re_ts":{}}}"#, id, now, re_ts);
+        let path = path.split('?').next().unwrap_or(path);
+        let (action, id) = match path.trim_matches('/').split_once('/') {
+            Some((action, id)) => (action, id.parse::<WorkerId>().ok()),
+            None => (path.trim_matches('/'), None),
+        };
+        match (action, id) {
+            ("", None) => match self.claim(now) {
+                Some((id, re_ts)) => (200, lease(id, re_ts)),
+                None => (503, r#"{"error":"no free worker id"}"#.to_string()),
+            },
+            ("reverify", Some(id)) => match self.reverify(id, now) {
+                Some(re_ts) => (200, lease(id, re_ts)),
+                None => (410, r#"{"error":"worker id isn't leased"}"#.to_string()),
+            },
+            ("release", Some(id)) => {
+                self.release(id);
+                (204, String::new())
+            }
+            ("time", None) => (200, format!(r#"{{"ts":{}}}"#, now)),
+            _ => (404, r#"{"error":"not found"}"#.to_string()),
+        }
+    }
+}
+
+/// Reference coordinator bound to an address, see [Server::serve]
+#[derive(Debug)]
+pub struct Server {
+    listener: TcpListener,
+    leases: Arc<Mutex<Leases>>,
+}
+
+impl Server {
+    /// Binds to `addr`, leasing the worker ids of `id_range`
+    pub fn bind(addr: impl ToSocketAddrs, id_range: RangeInclusive<WorkerId>) -> io::Result<Self> {
+        if id_range.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty worker id range",
+            ));
+        }
+        Ok(Server {
+            listener: TcpListener::bind(addr)?,
+            leases: Arc::new(Mutex::new(Leases {
+                id_range,
+                expiry: BTreeMap::new(),
+            })),
+        })
+    }
+
+    /// Bound address, e.g. the port picked for `127.0.0.1:0`
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves requests until accepting a connection fails, every connection on its own thread
+    pub fn serve(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let leases = self.leases.clone();
+            thread::spawn(move || {
+                if let Err(e) = respond(stream, &leases) {
+                    log::debug!("Couldn't answer coordinator request: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Runs a reference coordinator on `addr` leasing the worker ids of `id_range`, blocks while
+/// serving
+pub fn run(addr: impl ToSocketAddrs, id_range: RangeInclusive<WorkerId>) -> io::Result<()> {
+    Server::bind(addr, id_range)?.serve()
+}
+
+/// Answers a single request on `stream`
+fn respond(mut stream: TcpStream, leases: &Mutex<Leases>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let path = request_line.split(' ').nth(1).unwrap_or("/");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    let (status, body) = leases
+        .lock()
+        .expect("Couldn't lock leases")
+        .handle(path, now);
+    write!(
+        stream,
+        "HTTP/1.1 {} Coordinator\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::server::{Leases, Server, LEASE_SECS};
+    use crate::Generator;
+    use std::collections::{BTreeMap, HashSet};
+    use std::thread;
+
+    #[test]
+    pub fn test_leases() {
+        let mut leases = Leases {
+            id_range: 3..=4,
+            expiry: BTreeMap::new(),
+        };
+        assert_eq!(leases.handle("/", 100).0, 200);
+        assert_eq!(leases.claim(100), Some((4, 100 + LEASE_SECS)));
+        assert_eq!(leases.handle("/", 100).0, 503);
+        assert_eq!(leases.reverify(3, 200), Some(200 + LEASE_SECS));
+        // Expired leases can't be renewed, but are handed out again
+        assert_eq!(leases.handle("/reverify/4", 101 + LEASE_SECS).0, 410);
+        assert_eq!(
+            leases.claim(101 + LEASE_SECS),
+            Some((4, 101 + 2 * LEASE_SECS))
+        );
+        assert_eq!(leases.handle("/release/3", 300), (204, String::new()));
+        assert_eq!(leases.handle("/reverify/3", 300).0, 410);
+        assert_eq!(leases.claim(300), Some((3, 300 + LEASE_SECS)));
+        assert_eq!(
+            leases.handle("/time", 300),
+            (200, r#"{"ts":300}"#.to_string())
+        );
+        assert_eq!(leases.handle("/unknown/1", 300).0, 404);
+    }
+
+    #[tokio::test]
+    pub async fn test_end_to_end() {
+        let server = Server::bind("127.0.0.1:0", 10..=11).unwrap();
+        let url = format!("http://{}", server.local_addr().unwrap());
+        thread::spawn(move || server.serve());
+
+        let first = Generator::new(&url);
+        let second = Generator::new(&url);
+        let mut snowflakes = first.generate_batch(0, 1_000).await;
+        snowflakes.extend(second.generate_batch(0, 1_000).await);
+        let worker_ids: HashSet<_> = snowflakes.iter().map(|s| s.worker_id).collect();
+        assert_eq!(worker_ids, vec![10, 11].into_iter().collect());
+        let unique: HashSet<_> = snowflakes.into_iter().collect();
+        assert_eq!(unique.len(), 2_000);
+        assert_eq!(first.reverify_state().last_error, None);
+        let (start, end) = (1, 0);
+        assert!(Server::bind("127.0.0.1:0", start..=end).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Process wide registry naming usage ids, e.g. by the region they mark
+
+use crate::UsageId;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// Names of registered usage ids
+static NAMES: Lazy<RwLock<BTreeMap<UsageId, String>>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// Names `usage_id`, replacing an earlier name
+pub(crate) fn register(usage_id: UsageId, name: impl Into<String>) {
+    NAMES
+        .write()
+        .expect("Couldn't lock usage registry")
+        .insert(usage_id, name.into());
+}
+
+/// Name of `usage_id` if it's registered
+pub(crate) fn name(usage_id: UsageId) -> Option<String> {
+    NAMES
+        .read()
+        .expect("Couldn't lock usage registry")
+        .get(&usage_id)
+        .cloned()
+}
+
+/// Usage id named `name`, the lowest one if several are
+pub(crate) fn resolve(name: &str) -> Option<UsageId> {
+    NAMES
+        .read()
+        .expect("Couldn't lock usage registry")
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(usage_id, _)| *usage_id)
+}
+
+#[allow(clippy::unwrap_used)]
+#[cfg(test)]
+mod tests {
+    use crate::usage::{name, register, resolve};
+
+    #[test]
+    pub fn test_register() {
+        assert_eq!(name(231), None);
+        register(231, "eu");
+        assert_eq!(name(231).as_deref(), Some("eu"));
+        register(231, "eu-west");
+        assert_eq!(name(231).as_deref(), Some("eu-west"));
+        assert_eq!(resolve("eu-west"), Some(231));
+        assert_eq!(resolve("eu"), None);
+    }
+}